@@ -0,0 +1,63 @@
+//! Measures the time to build a Lexer with many rules
+//!
+//! Run with `cargo bench --bench build_time`. Compares building a Lexer
+//! against compiling every rule twice, once in a rule set and once on its
+//! own, as [LexerBuilder::build] did before rules were compiled on demand.
+//! Building again from an unchanged builder reuses the compiled regexes.
+
+use std::time::{Duration, Instant};
+
+use regex_automata::{meta, MatchKind};
+use runtime_lexer::lexer::LexerBuilder;
+
+const RULES: usize = 50;
+const BUILDS: u32 = 20;
+
+fn patterns() -> Vec<String> {
+    (0..RULES - 2).map(|i| format!(r"kw{}|[A-Z]{}\w*", i, i))
+        .chain([String::from(r"[a-z_]\w*"), String::from(r"\d+(\.\d+)?")])
+        .collect()
+}
+
+fn lexer(rebuild: bool) -> Duration {
+    let patterns = patterns();
+    let mut builder = LexerBuilder::<usize>::new();
+    for p in &patterns {
+        builder.push(p, |x: &str| x.len());
+    }
+
+    let start = Instant::now();
+    for i in 0..BUILDS {
+        // Toggling an option forces the regexes to be compiled again
+        builder.case_insensitive(!rebuild && i % 2 == 1);
+        let mut l = builder.build();
+        l.init_str("kw1 foo 42");
+        assert_eq!(l.count(), 3);
+    }
+    start.elapsed() / BUILDS
+}
+
+fn compile_twice() -> Duration {
+    let patterns = patterns();
+
+    let start = Instant::now();
+    for _ in 0..BUILDS {
+        let set = meta::Regex::builder()
+            .configure(meta::Config::new().match_kind(MatchKind::All))
+            .build_many(&patterns)
+            .unwrap();
+        let rules: Vec<_> = patterns.iter().map(|p| meta::Regex::new(p).unwrap()).collect();
+        assert_eq!(set.pattern_len(), rules.len());
+    }
+    start.elapsed() / BUILDS
+}
+
+fn report(name: &str, time: Duration) {
+    println!("{:<14} {:>10.2?} per build of {} rules", name, time, RULES);
+}
+
+fn main() {
+    report("lexer", lexer(false));
+    report("rebuild", lexer(true));
+    report("compile twice", compile_twice());
+}
@@ -0,0 +1,67 @@
+//! Measures the per token cost of a Lexer with many rules
+//!
+//! Run with `cargo bench`. Compares the Lexer, which only runs the rules its
+//! rule set reports as matching, against running every rule at every token.
+
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use runtime_lexer::lexer::LexerBuilder;
+
+const KEYWORDS: usize = 50;
+const TOKENS: usize = 60_000;
+
+fn input() -> String {
+    (0..TOKENS / 3).map(|i| format!("kw{} foo{} {} ", i % (KEYWORDS + 10), i, i)).collect()
+}
+
+fn patterns() -> Vec<String> {
+    (0..KEYWORDS).map(|i| format!("kw{}", i))
+        .chain([String::from(r"[a-z_]\w*"), String::from(r"\d+")])
+        .collect()
+}
+
+fn lexer(data: &str) -> (Duration, usize) {
+    let patterns = patterns();
+    let mut builder = LexerBuilder::<usize>::new();
+    for p in &patterns {
+        builder.push(p, |x: &str| x.len());
+    }
+    let mut l = builder.build();
+    l.init_str(data);
+
+    let start = Instant::now();
+    let count = l.count();
+    (start.elapsed(), count)
+}
+
+fn every_rule(data: &str) -> (Duration, usize) {
+    let ws = Regex::new(r"^\s+").unwrap();
+    let regexes: Vec<_> = patterns().iter().map(|p| Regex::new(&(String::from("^") + p)).unwrap()).collect();
+
+    let start = Instant::now();
+    let mut pos = 0;
+    let mut count = 0;
+    loop {
+        if let Some(m) = ws.find(&data[pos..]) {
+            pos += m.end();
+        }
+        let longest = regexes.iter().filter_map(|r| r.find(&data[pos..])).map(|m| m.end()).max().unwrap_or(0);
+        if longest == 0 {
+            break;
+        }
+        pos += longest;
+        count += 1;
+    }
+    (start.elapsed(), count)
+}
+
+fn report(name: &str, (time, count): (Duration, usize)) {
+    println!("{:<12} {:>10.2?} {:>8} tokens {:>8.0} ns/token", name, time, count, time.as_nanos() as f64 / count as f64);
+}
+
+fn main() {
+    let data = input();
+    report("lexer", lexer(&data));
+    report("every rule", every_rule(&data));
+}
@@ -3,231 +3,5871 @@
 //! A lexer based on the regex crate
 
 #[warn(missing_docs)]
+/// Contains the main lexer
+pub mod lexer {
+    use std::borrow::Cow;
+    use std::collections::VecDeque;
+    use std::cmp::Reverse;
+    use std::error::Error;
+    use std::fmt;
+    use std::ops::Range;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+    use regex::{Captures, Regex, RegexBuilder};
+    use regex_automata::{meta, Anchored, Input, MatchKind, PatternSet};
+    use regex_automata::util::syntax;
+    use regex_syntax::hir::{Hir, HirKind};
+    use lazy_static::lazy_static;
+
+    /// What the Lexer does with the input matched by a rule
+    ///
+    /// The closures do not have to be thread safe, see [SharedAction] for the
+    /// actions of a Lexer that is sent to other threads.
+    #[allow(clippy::type_complexity)]
+    pub enum Action<TokenType> {
+        /// Converts the matched `&str` to a `TokenType`
+        Token(Rc<dyn Fn(&str) -> TokenType>),
+        /// Converts the capture groups of the match to a `TokenType`
+        Captures(Rc<dyn Fn(&Captures) -> TokenType>),
+        /// Converts the matched `&str` to a `TokenType`, or declines the match
+        ///
+        /// If `None` is returned, the Lexer continues with the next shorter or
+        /// later rule that matched.
+        Fallible(Rc<dyn Fn(&str) -> Option<TokenType>>),
+        /// Converts the matched `&str` to any number of tokens
+        ///
+        /// The tokens are returned by consecutive calls to [tok](Lexer::tok),
+        /// all with the span of the match. The action is run once and the
+        /// tokens are queued. It only runs again if the tokens are needed
+        /// again, e.g. after restoring a [Checkpoint] in between them, so it
+        /// should not depend on its own side effects. An empty `Vec` consumes
+        /// the match like [Skip](Action::Skip).
+        Multi(Rc<dyn Fn(&str) -> Vec<TokenType>>),
+        /// Converts the matched `&str` to a `TokenType`, or fails with an error
+        ///
+        /// The error is returned by [try_tok](Lexer::try_tok) as a [LexError]
+        /// of kind [Action](LexErrorKind::Action) at the start of the match.
+        Try(Rc<dyn Fn(&str) -> Result<TokenType, Box<dyn Error>>>),
+        /// Converts the matched `&str` to a `TokenType`, also receiving the input after the match
+        ///
+        /// The action may return the number of bytes the token consumes from
+        /// the start of the match, which may be more or less than the match.
+        /// If it returns `None` the Lexer advances by the match as usual.
+        Contextual(Rc<dyn Fn(&str, &str) -> (TokenType, Option<usize>)>),
+        /// Converts the matched `&str` to a `TokenType` using a scratch buffer
+        ///
+        /// The buffer is owned by the Lexer and shared by all such rules. It
+        /// is cleared before every call but keeps its capacity, so building
+        /// temporary strings in it, e.g. to unescape a match before looking it
+        /// up, does not allocate for every token. Its contents are not kept
+        /// between calls.
+        Scratch(Rc<dyn Fn(&str, &mut String) -> TokenType>),
+        /// Consumes the match without producing a token
+        Skip,
+    }
+
+    /// What a [Shared] Lexer does with the input matched by a rule
+    ///
+    /// Like [Action], but all closures are `Send` and `Sync`.
+    #[allow(clippy::type_complexity)]
+    pub enum SharedAction<TokenType> {
+        /// See [Action::Token]
+        Token(Arc<dyn Fn(&str) -> TokenType + Send + Sync>),
+        /// See [Action::Captures]
+        Captures(Arc<dyn Fn(&Captures) -> TokenType + Send + Sync>),
+        /// See [Action::Fallible]
+        Fallible(Arc<dyn Fn(&str) -> Option<TokenType> + Send + Sync>),
+        /// See [Action::Multi]
+        Multi(Arc<dyn Fn(&str) -> Vec<TokenType> + Send + Sync>),
+        /// See [Action::Try]
+        Try(Arc<dyn Fn(&str) -> Result<TokenType, Box<dyn Error>> + Send + Sync>),
+        /// See [Action::Contextual]
+        Contextual(Arc<dyn Fn(&str, &str) -> (TokenType, Option<usize>) + Send + Sync>),
+        /// See [Action::Scratch]
+        Scratch(Arc<dyn Fn(&str, &mut String) -> TokenType + Send + Sync>),
+        /// See [Action::Skip]
+        Skip,
+    }
+
+    /// Whether the actions of a Lexer have to be thread safe
+    ///
+    /// A [Local] Lexer, the default, accepts any closure as an action, e.g.
+    /// one capturing an `Rc<RefCell<_>>`. A [Shared] Lexer only accepts `Send`
+    /// and `Sync` closures, and is `Send` and `Sync` itself if its
+    /// `TokenType` is. Start a shared Lexer with
+    /// `LexerBuilder::<TokenType, Shared>::default()`.
+    pub trait Sharing: sealed::Sealed + 'static {
+        /// The action of a rule, [Action] or [SharedAction]
+        type Action<TokenType>: Clone + sealed::View<TokenType, Sharing = Self>;
+    }
+
+    /// The actions may be any closure, see [Sharing]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Local;
+
+    /// The actions have to be `Send` and `Sync`, see [Sharing]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Shared;
+
+    impl Sharing for Local {
+        type Action<TokenType> = Action<TokenType>;
+    }
+
+    impl Sharing for Shared {
+        type Action<TokenType> = SharedAction<TokenType>;
+    }
+
+    /// Closures of type `F` a Lexer with this [Sharing] takes as actions
+    ///
+    /// [Local] takes every closure, [Shared] only `Send` and `Sync` ones.
+    #[diagnostic::on_unimplemented(message = "the actions of a `Shared` Lexer have to be `Send` and `Sync`")]
+    pub trait Accepts<F>: Sharing {
+        /// Wraps `action` in a [Token](Action::Token) action
+        fn token<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str) -> TokenType;
+        /// Wraps `make` in a [Token](Action::Token) action passing it the match as a `String`
+        fn owned<TokenType>(make: F) -> Self::Action<TokenType>
+        where F: Fn(String) -> TokenType;
+        /// Wraps a preprocessing function and an action in a [Token](Action::Token) action
+        fn preprocessed<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: sealed::Preprocess<TokenType>;
+        /// Wraps `action` in a [Captures](Action::Captures) action
+        fn captures<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&Captures) -> TokenType;
+        /// Wraps `action` in a [Fallible](Action::Fallible) action
+        fn fallible<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str) -> Option<TokenType>;
+        /// Wraps `action` in a [Multi](Action::Multi) action
+        fn multi<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str) -> Vec<TokenType>;
+        /// Wraps `action` in a [Try](Action::Try) action
+        fn try_token<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str) -> Result<TokenType, Box<dyn Error>>;
+        /// Wraps `action` in a [Contextual](Action::Contextual) action
+        fn contextual<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str, &str) -> (TokenType, Option<usize>);
+        /// Wraps `action` in a [Contextual](Action::Contextual) action passing it the rest of the line
+        fn rest_of_line<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str) -> TokenType;
+        /// Wraps `action` in a [Scratch](Action::Scratch) action
+        fn scratch<TokenType>(action: F) -> Self::Action<TokenType>
+        where F: Fn(&str, &mut String) -> TokenType;
+    }
+
+    /// Keeps [Sharing] from being implemented outside of this crate
+    mod sealed {
+        use std::borrow::Cow;
+        use std::error::Error;
+
+        use regex::Captures;
+
+        pub trait Sealed {}
+
+        /// Borrowed closure of an [Action](super::Action) or [SharedAction](super::SharedAction)
+        #[allow(clippy::type_complexity)]
+        pub enum ActionRef<'f, TokenType> {
+            Token(&'f dyn Fn(&str) -> TokenType),
+            Captures(&'f dyn Fn(&Captures) -> TokenType),
+            Fallible(&'f dyn Fn(&str) -> Option<TokenType>),
+            Multi(&'f dyn Fn(&str) -> Vec<TokenType>),
+            Try(&'f dyn Fn(&str) -> Result<TokenType, Box<dyn Error>>),
+            Contextual(&'f dyn Fn(&str, &str) -> (TokenType, Option<usize>)),
+            Scratch(&'f dyn Fn(&str, &mut String) -> TokenType),
+            Skip,
+        }
+
+        /// The action types of the [Sharing](super::Sharing) flavours
+        pub trait View<TokenType>: Sized {
+            type Sharing: super::Sharing;
+            fn view(&self) -> ActionRef<'_, TokenType>;
+            fn into_action(self) -> <Self::Sharing as super::Sharing>::Action<TokenType>;
+            fn skip() -> Self;
+        }
+
+        /// A preprocessing function and an action, see [push_with_preprocess](super::LexerBuilder::push_with_preprocess)
+        pub trait Preprocess<TokenType> {
+            fn call(&self, x: &str) -> TokenType;
+        }
+
+        impl<P, F, TokenType> Preprocess<TokenType> for (P, F)
+        where P: Fn(&str) -> Cow<'_, str>,
+              F: Fn(&str) -> TokenType,
+        {
+            fn call(&self, x: &str) -> TokenType {
+                (self.1)(&(self.0)(x))
+            }
+        }
+    }
+
+    use sealed::{ActionRef, View};
+
+    /// Returns the rest of the line in `after` and the length of the token, see [push_rest_of_line](LexerBuilder::push_rest_of_line)
+    fn rest_of_line<'t>(matched: &str, after: &'t str) -> (&'t str, usize) {
+        let line = &after[..after.find('\n').unwrap_or(after.len())];
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        (line.trim_start_matches([' ', '\t']), matched.len() + line.len())
+    }
+
+    /// Implements the traits of a [Sharing] flavour for its action type
+    macro_rules! sharing {
+        ($sharing:ident, $action:ident, $ptr:ident, $($bound:tt)*) => {
+            impl sealed::Sealed for $sharing {}
+
+            impl<TokenType> Clone for $action<TokenType> {
+                fn clone(&self) -> Self {
+                    match self {
+                        $action::Token(f) => $action::Token(f.clone()),
+                        $action::Captures(f) => $action::Captures(f.clone()),
+                        $action::Fallible(f) => $action::Fallible(f.clone()),
+                        $action::Multi(f) => $action::Multi(f.clone()),
+                        $action::Try(f) => $action::Try(f.clone()),
+                        $action::Contextual(f) => $action::Contextual(f.clone()),
+                        $action::Scratch(f) => $action::Scratch(f.clone()),
+                        $action::Skip => $action::Skip,
+                    }
+                }
+            }
+
+            impl<TokenType> View<TokenType> for $action<TokenType> {
+                type Sharing = $sharing;
+
+                fn view(&self) -> ActionRef<'_, TokenType> {
+                    match self {
+                        $action::Token(f) => ActionRef::Token(&**f),
+                        $action::Captures(f) => ActionRef::Captures(&**f),
+                        $action::Fallible(f) => ActionRef::Fallible(&**f),
+                        $action::Multi(f) => ActionRef::Multi(&**f),
+                        $action::Try(f) => ActionRef::Try(&**f),
+                        $action::Contextual(f) => ActionRef::Contextual(&**f),
+                        $action::Scratch(f) => ActionRef::Scratch(&**f),
+                        $action::Skip => ActionRef::Skip,
+                    }
+                }
+
+                fn into_action(self) -> Self {
+                    self
+                }
+
+                fn skip() -> Self {
+                    $action::Skip
+                }
+            }
+
+            impl<F: $($bound)*> Accepts<F> for $sharing {
+                fn token<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str) -> TokenType {
+                    $action::Token($ptr::new(action))
+                }
+
+                fn owned<TokenType>(make: F) -> $action<TokenType>
+                where F: Fn(String) -> TokenType {
+                    $action::Token($ptr::new(move |x: &str| make(String::from(x))))
+                }
+
+                fn preprocessed<TokenType>(action: F) -> $action<TokenType>
+                where F: sealed::Preprocess<TokenType> {
+                    $action::Token($ptr::new(move |x: &str| action.call(x)))
+                }
+
+                fn captures<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&Captures) -> TokenType {
+                    $action::Captures($ptr::new(action))
+                }
+
+                fn fallible<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str) -> Option<TokenType> {
+                    $action::Fallible($ptr::new(action))
+                }
+
+                fn multi<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str) -> Vec<TokenType> {
+                    $action::Multi($ptr::new(action))
+                }
+
+                fn try_token<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str) -> Result<TokenType, Box<dyn Error>> {
+                    $action::Try($ptr::new(action))
+                }
+
+                fn contextual<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str, &str) -> (TokenType, Option<usize>) {
+                    $action::Contextual($ptr::new(action))
+                }
+
+                fn rest_of_line<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str) -> TokenType {
+                    $action::Contextual($ptr::new(move |matched: &str, after: &str| {
+                        let (line, len) = rest_of_line(matched, after);
+                        (action(line), Some(len))
+                    }))
+                }
+
+                fn scratch<TokenType>(action: F) -> $action<TokenType>
+                where F: Fn(&str, &mut String) -> TokenType {
+                    $action::Scratch($ptr::new(action))
+                }
+            }
+        };
+    }
+
+    sharing!(Local, Action, Rc, 'static);
+    sharing!(Shared, SharedAction, Arc, Send + Sync + 'static);
+
+    /// Result of running an [Action]
+    enum Outcome<TokenType> {
+        Token(TokenType),
+        Tokens(Vec<TokenType>),
+        /// A token consuming the given number of bytes instead of the match
+        Resized(TokenType, usize),
+        Failed(Box<dyn Error>),
+        Skip,
+        Decline,
+    }
+
+    /// Represents a Lexer Action mapping a regex representation to a TokenType
+    #[derive(Clone)]
+    pub struct LexAction<'s, TokenType, S: Sharing = Local> {
+        /// Regex representation of a token
+        pub token:  Cow<'s, str>,
+        /// What to do with the input matched by `token`
+        pub action: S::Action<TokenType>,
+        /// Inline flags applied only to `token`, e.g. `"i"` or `"ms"`
+        pub flags: Option<&'s str>,
+        /// Regex representation of input that has to follow `token` without being consumed
+        pub follow: Option<&'s str>,
+        /// Wins ties on the length of the match against rules with a lower priority
+        ///
+        /// Rules with the same priority are tried in insertion order.
+        pub priority: i32,
+        /// Display name of the rule, e.g. `"identifier"`, see [rule_name](Lexer::rule_name)
+        pub name: Option<&'s str>,
+        /// Lexical mode the rule is active in, None for the default mode
+        pub mode: Option<&'s str>,
+        /// Change of the mode after the rule matched, see [push_transition](LexerBuilder::push_transition)
+        pub mode_change: Option<ModeChange<'s>>,
+        /// Whether the longest match of `token` wins instead of the first alternative, see [push_any](LexerBuilder::push_any)
+        pub longest: bool,
+    }
+
+    impl<'s, TokenType, S: Sharing> LexAction<'s, TokenType, S> {
+        /// Returns a LexAction without flags, lookahead or name and with priority 0
+        ///
+        /// The rule is active in the default mode and does not change the mode.
+        /// The first matching alternative of `token` wins, as in the regex crate.
+        /// `action` is an [Action], or a [SharedAction] for a [Shared] Lexer.
+        pub fn new<T: Into<Cow<'s, str>>, A: View<TokenType, Sharing = S>>(token: T, action: A) -> Self {
+            LexAction{ token: token.into(), action: action.into_action(), flags: None, follow: None, priority: 0, name: None, mode: None, mode_change: None, longest: false }
+        }
+    }
+
+    /// Change of the lexical mode after a rule matched
+    ///
+    /// The Lexer keeps a stack of modes and only tries the rules of the mode
+    /// on top, or the rules of the default mode if the stack is empty.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ModeChange<'s> {
+        /// Enters the mode, e.g. at the start of a string literal
+        Push(&'s str),
+        /// Returns to the previous mode
+        Pop,
+    }
+
+    /// Owned [ModeChange] of a built Lexer
+    #[derive(Clone)]
+    enum Transition {
+        Push(String),
+        Pop,
+    }
+
+    /// Description of a rule that can be stored outside of the program, e.g. in a config file
+    ///
+    /// The action is identified by `tag`, see [from_specs](LexerBuilder::from_specs).
+    /// With the `serde` feature it can be serialized, e.g. to load the rules
+    /// from JSON.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct RuleSpec {
+        /// Regex representation of the token
+        pub pattern: String,
+        /// Name of the action converting the token
+        pub tag: String,
+    }
+
+    /// How the Lexer chooses between several rules matching at a position
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum Strategy {
+        /// The first rule in insertion order that matches wins
+        First,
+        /// The longest match wins, ties are won by the first rule in insertion order
+        #[default]
+        Longest,
+    }
+
+    /// Function producing tokens at the start of a line, see [on_line_start](LexerBuilder::on_line_start)
+    pub type LineStartHook<TokenType> = fn(usize, &mut Vec<usize>) -> Vec<TokenType>;
+
+    /// Struct used to generate a Lexer
+    ///
+    /// It can either be initialised with an array of LexActions, or using the
+    /// [push](LexerBuilder::push) method(recommended).
+    pub struct LexerBuilder<'s, TokenType, S: Sharing = Local> {
+        /// List of all tokens including conversions used by the resulting Lexer
+        pub actions: Vec<LexAction<'s, TokenType, S>>,
+        /// Regex representation of the whitespace skipped by `tok(true)`, `\s+` if unset
+        pub whitespace: Option<&'s str>,
+        /// Regex representations of further input skipped together with the whitespace, e.g. comments
+        pub ignored: Vec<&'s str>,
+        /// Whether the default whitespace leaves `\n` to the rules
+        pub significant_newlines: bool,
+        /// Whether all patterns are matched case insensitively
+        pub case_insensitive: bool,
+        /// Whether `.` matches `\n` in all patterns
+        pub dot_matches_newline: bool,
+        /// Whether `^` and `$` match at the start and end of lines in all patterns
+        pub multi_line: bool,
+        /// Function creating the token returned once at the end of input
+        pub eof_token: Option<fn() -> TokenType>,
+        /// Function called when several rules tie for the longest match
+        pub on_ambiguity: Option<fn(&[usize], &str)>,
+        /// Function creating the token returned for a char no rule matches
+        pub error_token: Option<fn(&str) -> TokenType>,
+        /// Function producing tokens before the first token of each line
+        pub on_line_start: Option<LineStartHook<TokenType>>,
+        /// Maximum number of bytes a single token may consume
+        pub max_token_len: Option<usize>,
+        /// Approximate maximum size of each compiled regex in bytes
+        pub size_limit: Option<usize>,
+        /// Approximate maximum size of the cache of the lazy DFA of each regex in bytes
+        pub dfa_size_limit: Option<usize>,
+        /// How the rule producing a token is chosen
+        pub match_strategy: Strategy,
+        /// Whether [next_token](Lexer::next_token) skips whitespace
+        pub default_skip_ws: bool,
+        /// Regexes of the last build, reused while the configuration is unchanged
+        pub cache: RegexCache,
+        /// Duplicates dropped by [from_actions](LexerBuilder::from_actions), reported by [validate](LexerBuilder::validate)
+        pub dropped: Vec<Warning>,
+    }
+
+    /// Represents a finished Lexer
+    ///
+    /// The lifetime `'a` is the lifetime of input borrowed through
+    /// [init_str](Lexer::init_str).
+    ///
+    /// A [Shared] Lexer is `Send` and `Sync` if `TokenType` is, see [Sharing].
+    /// See [clone_fresh](Lexer::clone_fresh) to lex on several threads.
+    ///
+    /// All regexes are run anchored at the current position, but against the
+    /// whole input, so assertions like `\b` or `$` see the surrounding text.
+    /// This includes the text outside of a range lexed with
+    /// [init_range](Lexer::init_range).
+    pub struct Lexer<'a, TokenType, S: Sharing = Local> {
+        pub(crate) compiled: Arc<Compiled>,
+        /// Scratch space for the rules matching at the current position
+        matched: PatternSet,
+        actions: Vec<S::Action<TokenType>>,
+        priorities: Vec<i32>,
+        names: Vec<Option<String>>,
+        /// Whether each rule takes part in matching, see [set_rule_enabled](Lexer::set_rule_enabled)
+        enabled: Vec<bool>,
+        /// Mode each rule is active in, None for the default mode
+        modes: Vec<Option<String>>,
+        /// Change of the mode after each rule matched
+        transitions: Vec<Option<Transition>>,
+        /// Buffer passed to [Scratch](Action::Scratch) actions
+        scratch: Mutex<String>,
+        eof_token: Option<fn() -> TokenType>,
+        on_ambiguity: Option<fn(&[usize], &str)>,
+        error_token: Option<fn(&str) -> TokenType>,
+        on_line_start: Option<LineStartHook<TokenType>>,
+        max_token_len: Option<usize>,
+        match_strategy: Strategy,
+        skip_ws: bool,
+        data: Cow<'a, str>,
+        /// Range of the data that is lexed
+        start: usize,
+        end: usize,
+        curr_pos: usize,
+        line: usize,
+        column: usize,
+        /// Span of the most recently produced token
+        last_span: Option<Range<usize>>,
+        /// Rule that produced the most recently produced token, None for the EOF and error tokens
+        last_rule: Option<usize>,
+        /// State in front of the most recently produced token, restored by [unput](Lexer::unput)
+        before_last: Option<State>,
+        /// Whether the EOF token has been returned
+        eof_done: bool,
+        /// Tokens of a [Multi](Action::Multi) match that are still to be returned
+        expansion: Option<Expansion>,
+        /// Indentation levels passed to the line start hook
+        indents: Vec<usize>,
+        /// Line the line start hook last ran for, `usize::MAX` once it ran for the end of input
+        line_started: usize,
+        /// Tokens of the line start hook that are still to be returned
+        line_tokens: Option<LineTokens>,
+        /// Tokens of the current expansion or line start hook produced at once
+        queued: Option<Queued<TokenType>>,
+        /// Entered lexical modes, the active one last
+        mode_stack: Vec<String>,
+        /// Whether more input may be fed
+        streaming: bool,
+        /// Tokens lexed ahead by [peek_n](Lexer::peek_n)
+        lookahead: VecDeque<Lookahead<TokenType>>,
+        /// Error of the most recent attempt to lex a token
+        last_error: Option<LexError>,
+    }
+
+    /// Clones the Lexer including its input and current position
+    ///
+    /// The compiled regexes are shared between the clones, so this is cheap
+    /// apart from copying owned input. Tokens lexed ahead, e.g. by
+    /// [peek](Lexer::peek), are not copied, so `TokenType` does not need to be
+    /// `Clone`. The clone lexes them again, running their actions once more.
+    impl<TokenType, S: Sharing> Clone for Lexer<'_, TokenType, S> {
+        fn clone(&self) -> Self {
+            self.with_data(self.data.clone())
+        }
+    }
+
+    impl<TokenType, S: Sharing> Lexer<'_, TokenType, S> {
+        /// Copies the Lexer, replacing its input by `data`
+        fn with_data<'b>(&self, data: Cow<'b, str>) -> Lexer<'b, TokenType, S> {
+            Lexer{
+                compiled: self.compiled.clone(),
+                matched: self.matched.clone(),
+                actions: self.actions.clone(),
+                priorities: self.priorities.clone(),
+                names: self.names.clone(),
+                enabled: self.enabled.clone(),
+                modes: self.modes.clone(),
+                transitions: self.transitions.clone(),
+                scratch: Mutex::new(String::new()),
+                eof_token: self.eof_token,
+                on_ambiguity: self.on_ambiguity,
+                error_token: self.error_token,
+                on_line_start: self.on_line_start,
+                max_token_len: self.max_token_len,
+                match_strategy: self.match_strategy,
+                skip_ws: self.skip_ws,
+                data,
+                start: self.start,
+                end: self.end,
+                curr_pos: self.curr_pos,
+                line: self.line,
+                column: self.column,
+                last_span: self.last_span.clone(),
+                last_rule: self.last_rule,
+                before_last: self.before_last.clone(),
+                eof_done: self.eof_done,
+                expansion: self.expansion.clone(),
+                indents: self.indents.clone(),
+                line_started: self.line_started,
+                line_tokens: self.line_tokens.clone(),
+                // The tokens are produced again if the copy needs them
+                queued: None,
+                mode_stack: self.mode_stack.clone(),
+                streaming: self.streaming,
+                // The position is not moved by lexing ahead, so the copy can
+                // simply lex the buffered tokens again
+                lookahead: VecDeque::new(),
+                last_error: self.last_error.clone(),
+            }
+        }
+    }
+
+    /// Shows the position and the start of the remaining input, but not the rules
+    impl<TokenType, S: Sharing> fmt::Debug for Lexer<'_, TokenType, S> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Lexer")
+                .field("rules", &self.compiled.rules.len())
+                .field("offset", &self.curr_pos)
+                .field("line", &self.line)
+                .field("column", &self.column)
+                .field("mode", &self.mode())
+                .field("len", &self.data.len())
+                .field("remaining", &self.remaining().chars().take(SNIPPET_LEN).collect::<String>())
+                .finish()
+        }
+    }
+
+    /// Regexes compiled from the rules of a [LexerBuilder]
+    #[derive(Clone)]
+    pub(crate) struct Compiled {
+        whitespace: meta::Regex,
+        /// All rules at once, used to find the rules matching at a position
+        rule_set: meta::Regex,
+        /// Each rule on its own, used to find the length of its match
+        ///
+        /// Compiled from `patterns` when the rule first matches, as the rule
+        /// set already checked that all patterns are valid.
+        rules: Vec<OnceLock<meta::Regex>>,
+        /// Whether the longest match of each rule wins, see [LexAction::longest]
+        longest: Vec<bool>,
+        /// Patterns as compiled, including the flags of a rule
+        patterns: Vec<String>,
+        /// Patterns as pushed
+        sources: Vec<String>,
+        syntax: syntax::Config,
+        /// Limits all regexes are compiled with
+        config: meta::Config,
+        /// Rules with a [Captures](Action::Captures) action compiled with the regex crate
+        captures: Vec<Option<Regex>>,
+        follows: Vec<Option<meta::Regex>>,
+    }
+
+    /// Configuration of a [LexerBuilder] the [Compiled] regexes depend on
+    #[derive(PartialEq)]
+    struct CompileKey {
+        /// Pattern, flags, lookahead, whether captures are needed and whether the longest match wins of each rule
+        #[allow(clippy::type_complexity)]
+        rules: Vec<(String, Option<String>, Option<String>, bool, bool)>,
+        whitespace: Option<String>,
+        ignored: Vec<String>,
+        significant_newlines: bool,
+        case_insensitive: bool,
+        dot_matches_newline: bool,
+        multi_line: bool,
+        size_limit: Option<usize>,
+        dfa_size_limit: Option<usize>,
+    }
+
+    /// Regexes compiled by [try_build](LexerBuilder::try_build), see [cache](LexerBuilder::cache)
+    #[derive(Default)]
+    pub struct RegexCache(Mutex<Option<(CompileKey, Arc<Compiled>)>>);
+
+    /// Position dependent state of a Lexer, see [checkpoint](Lexer::checkpoint)
+    #[derive(Clone)]
+    pub struct Checkpoint {
+        state: State,
+        last_span: Option<Range<usize>>,
+        last_rule: Option<usize>,
+        before_last: Option<State>,
+    }
+
+    /// Position dependent state of a Lexer apart from the most recent token
+    #[derive(Clone)]
+    struct State {
+        curr_pos: usize,
+        line: usize,
+        column: usize,
+        eof_done: bool,
+        expansion: Option<Expansion>,
+        indents: Vec<usize>,
+        line_started: usize,
+        line_tokens: Option<LineTokens>,
+        mode_stack: Vec<String>,
+    }
+
+    /// Position within the tokens produced by a [Multi](Action::Multi) match
+    #[derive(Clone)]
+    struct Expansion {
+        rule: usize,
+        span: Range<usize>,
+        /// Index of the next token to return
+        next: usize,
+    }
+
+    /// Position within the tokens produced by the line start hook
+    #[derive(Clone)]
+    struct LineTokens {
+        indent: usize,
+        /// Indentation levels before the hook ran
+        indents: Vec<usize>,
+        /// Index of the next token to return
+        next: usize,
+    }
+
+    /// Tokens produced at once by a [Multi](Action::Multi) action or the line start hook
+    struct Queued<TokenType> {
+        /// Rule of the match, None for the line start hook, and the position the tokens were produced at
+        source: (Option<usize>, usize),
+        /// Index of the first token in `tokens`
+        next: usize,
+        tokens: VecDeque<TokenType>,
+    }
+
+    /// Token lexed ahead, with the state of the Lexer after it
+    struct Lookahead<TokenType> {
+        skip_ws: bool,
+        token: TokenType,
+        span: Range<usize>,
+        after: Checkpoint,
+    }
+
+    /// Reason of a [LexError]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LexErrorKind {
+        /// The input at the position matches no token
+        Unmatched,
+        /// A token was lexed, but it is not the expected one
+        Unexpected,
+        /// The end of input was reached, but a token was expected
+        UnexpectedEof,
+        /// The action of the matching rule failed, see [Try](Action::Try)
+        Action,
+        /// The chosen match is longer than allowed by [max_token_len](LexerBuilder::max_token_len)
+        TooLong,
+        /// The input fed so far ends before the next token could be completed
+        ///
+        /// Only returned after [feed](Lexer::feed), until [finish](Lexer::finish) is called.
+        Incomplete,
+    }
+
+    /// Error returned when the input can not be lexed as requested
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct LexError {
+        /// Why lexing failed
+        pub kind: LexErrorKind,
+        /// Byte position of the offending input
+        pub position: usize,
+        /// 1-based line of the offending input
+        pub line: usize,
+        /// 1-based column of the offending input, counted in chars
+        pub column: usize,
+        /// Start of the offending input, or the text of the offending token
+        pub snippet: String,
+        /// Error reported by a failed action
+        pub message: Option<String>,
+    }
+
+    /// Maximum number of chars stored in [LexError::snippet]
+    const SNIPPET_LEN: usize = 16;
+
+    impl fmt::Display for LexError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let reason = match self.kind {
+                LexErrorKind::Unmatched => "no token matches",
+                LexErrorKind::Unexpected => "unexpected token",
+                LexErrorKind::UnexpectedEof => "unexpected end of input",
+                LexErrorKind::Action => "invalid token",
+                LexErrorKind::TooLong => "token too long",
+                LexErrorKind::Incomplete => "incomplete input",
+            };
+            write!(f, "{} at {}:{} (byte {}): `{}`", reason, self.line, self.column, self.position, self.snippet)?;
+            match &self.message {
+                Some(message) => write!(f, ": {}", message),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Error for LexError {}
+
+    /// Error returned when a Lexer can not be built
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct BuildError {
+        /// Regex representation of the offending rule, empty if there are no rules
+        pub pattern: String,
+        /// Index of the offending rule, None if the pattern is not a rule, e.g. the whitespace
+        pub rule: Option<usize>,
+        /// Reason the Lexer can not be built
+        pub kind: BuildErrorKind,
+    }
+
+    /// Reason of a [BuildError]
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BuildErrorKind {
+        /// The pattern is rejected by the regex crate
+        Regex(regex::Error),
+        /// The pattern of the rule only produces empty matches, e.g. `|abc`
+        EmptyMatch,
+        /// No rule has been added to the builder
+        NoRules,
+    }
+
+    impl fmt::Display for BuildErrorKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BuildErrorKind::Regex(error) => write!(f, "{}", error),
+                BuildErrorKind::EmptyMatch => write!(f, "the pattern only matches the empty string"),
+                BuildErrorKind::NoRules => write!(f, "no rules have been added"),
+            }
+        }
+    }
+
+    impl fmt::Display for BuildError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if self.pattern.is_empty() {
+                write!(f, "invalid lexer: {}", self.kind)
+            } else if let Some(rule) = self.rule {
+                write!(f, "invalid pattern `{}` of rule {}: {}", self.pattern, rule, self.kind)
+            } else {
+                write!(f, "invalid pattern `{}`: {}", self.pattern, self.kind)
+            }
+        }
+    }
+
+    impl BuildError {
+        fn from_meta(pattern: &str, e: meta::BuildError) -> Self {
+            let error = match e.size_limit() {
+                Some(limit) => regex::Error::CompiledTooBig(limit),
+                None => regex::Error::Syntax(e.to_string()),
+            };
+            BuildError{ pattern: String::from(pattern), rule: None, kind: BuildErrorKind::Regex(error) }
+        }
+
+        fn no_rules() -> Self {
+            BuildError{ pattern: String::new(), rule: None, kind: BuildErrorKind::NoRules }
+        }
+    }
+
+    impl Error for BuildError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match &self.kind {
+                BuildErrorKind::Regex(error) => Some(error),
+                BuildErrorKind::EmptyMatch | BuildErrorKind::NoRules => None,
+            }
+        }
+    }
+
+    /// Potential mistake in the rules of a [LexerBuilder], see [validate](LexerBuilder::validate)
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Warning {
+        /// Index of the rule that can never produce a token
+        pub rule: usize,
+        /// Pattern of that rule
+        pub pattern: String,
+        /// Index of the earlier rule winning instead
+        pub shadowed_by: usize,
+    }
+
+    impl fmt::Display for Warning {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "rule {} `{}` is shadowed by rule {}", self.rule, self.pattern, self.shadowed_by)
+        }
+    }
+
+    /// Error returned when seeking to a position that is not a char boundary of the input
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SeekError {
+        /// Requested byte position
+        pub position: usize,
+    }
+
+    impl fmt::Display for SeekError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "can not seek to byte {}", self.position)
+        }
+    }
+
+    impl Error for SeekError {}
+
+    /// Error returned when a [RuleSpec] refers to an unknown action
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SpecError {
+        /// Tag that has no action
+        pub tag: String,
+    }
+
+    impl fmt::Display for SpecError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "no action for tag `{}`", self.tag)
+        }
+    }
+
+    impl Error for SpecError {}
+
+    impl<'s, TokenType: 'static, S: Accepts<fn(&str) -> TokenType>> FromIterator<(&'s str, fn(&str) -> TokenType)> for LexerBuilder<'s, TokenType, S> {
+        fn from_iter<I: IntoIterator<Item = (&'s str, fn(&str) -> TokenType)>>(rules: I) -> Self {
+            let mut builder = Self::default();
+            builder.extend(rules);
+            builder
+        }
+    }
+
+    /// Returns `token` with the per rule `flags` applied
+    fn pattern(token: &str, flags: Option<&str>) -> String {
+        match flags {
+            Some(flags) => format!("(?{}:{})", flags, token),
+            None => String::from(token),
+        }
+    }
+
+    /// Returns a builder for the rule set reporting which patterns match at a position
+    fn rule_set_builder(config: &meta::Config, syntax: syntax::Config) -> meta::Builder {
+        let mut builder = meta::Regex::builder();
+        builder.configure(config.clone().match_kind(MatchKind::All)).syntax(syntax);
+        builder
+    }
+
+    /// Returns the line and column after `text`, starting at `line_col`
+    fn advance_line_col(text: &str, (mut line, mut column): (usize, usize)) -> (usize, usize) {
+        for c in text.chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        (line, column)
+    }
+
+    /// Returns an Input searching `data` anchored at `pos`, up to `end`
+    fn anchored(data: &str, pos: usize, end: usize) -> Input<'_> {
+        Input::new(data).range(pos..end).anchored(Anchored::Yes)
+    }
+
+    /// Returns the end of the match of `regex` starting exactly at `pos`
+    fn match_at(regex: &meta::Regex, data: &str, pos: usize, end: usize) -> Option<usize> {
+        regex.search(&anchored(data, pos, end)).map(|m| m.end())
+    }
+
+    /// Fails if every match of `pattern`, the compiled form of rule `rule`, is
+    /// empty, as such a rule would never produce a token
+    ///
+    /// Rules that can also match something else, like `\d*`, are kept.
+    fn check_non_empty(rule: usize, token: &str, pattern: &str, longest: bool, syntax: &syntax::Config) -> Result<(), BuildError> {
+        let kind = match syntax::parse_with(pattern, syntax) {
+            Ok(hir) if hir.properties().maximum_len() == Some(0) || (!longest && prefers_empty(&hir)) => BuildErrorKind::EmptyMatch,
+            Ok(_) => return Ok(()),
+            Err(e) => BuildErrorKind::Regex(regex::Error::Syntax(e.to_string())),
+        };
+        Err(BuildError{ pattern: String::from(token), rule: Some(rule), kind })
+    }
+
+    /// Returns whether `hir` matches the empty string at any position and prefers it
+    /// to any longer match, e.g. `|abc` as the first alternative wins
+    fn prefers_empty(hir: &Hir) -> bool {
+        match hir.kind() {
+            HirKind::Empty => true,
+            HirKind::Capture(capture) => prefers_empty(&capture.sub),
+            HirKind::Concat(hirs) => hirs.iter().all(prefers_empty),
+            HirKind::Alternation(hirs) => prefers_empty(&hirs[0]),
+            HirKind::Repetition(repetition) => (repetition.min == 0 && !repetition.greedy) || prefers_empty(&repetition.sub),
+            HirKind::Literal(_) | HirKind::Class(_) | HirKind::Look(_) => false,
+        }
+    }
+
+    /// Lexes a single token at the start of `input` with `rules`
+    ///
+    /// Returns the token and the number of bytes it consumed, or None if no
+    /// rule matches or the rules are invalid. Whitespace is not skipped. Builds
+    /// a Lexer for the call, so use a [LexerBuilder] to lex more than once.
+    pub fn lex_one<TokenType>(rules: &[LexAction<'_, TokenType>], input: &str) -> Option<(TokenType, usize)> {
+        let builder = LexerBuilder{
+            actions: rules.iter().map(|a| LexAction{ token: a.token.clone(), action: a.action.clone(), ..*a }).collect(),
+            ..Default::default()
+        };
+        let mut lexer = builder.try_build().ok()?;
+        lexer.init_str(input);
+        lexer.tok_spanned(false).map(|(token, span)| (token, span.end))
+    }
+
+    /// Shows the patterns of all rules, but not their actions
+    impl<TokenType, S: Sharing> fmt::Debug for LexerBuilder<'_, TokenType, S> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("LexerBuilder")
+                .field("patterns", &self.actions.iter().map(|a| &a.token).collect::<Vec<_>>())
+                .field("whitespace", &self.whitespace)
+                .field("case_insensitive", &self.case_insensitive)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<'s, TokenType, S: Sharing> Default for LexerBuilder<'s, TokenType, S> {
+        fn default() -> Self {
+            LexerBuilder{ actions: Vec::new(), whitespace: None, ignored: Vec::new(), significant_newlines: false, case_insensitive: false, dot_matches_newline: false, multi_line: false, eof_token: None, on_ambiguity: None, error_token: None, on_line_start: None, max_token_len: None, size_limit: None, dfa_size_limit: None, match_strategy: Strategy::Longest, default_skip_ws: false, cache: RegexCache::default(), dropped: Vec::new() }
+        }
+    }
+
+    impl<'s, TokenType> LexerBuilder<'s, TokenType> {
+        /// Returns an empty LexerBuilder
+        ///
+        /// The actions of the Lexer may be any closure, see [Sharing] for a
+        /// Lexer that is sent to other threads.
+        pub fn new() -> Self{
+            Self::default()
+        }
+
+        /// Creates a LexerBuilder from rule descriptions
+        ///
+        /// The action of each rule is looked up by its tag in `actions`.
+        #[allow(clippy::type_complexity)]
+        pub fn from_specs(specs: &'s [RuleSpec], actions: &[(&str, fn(&str) -> TokenType)]) -> Result<Self, SpecError>
+        where TokenType: 'static,
+        {
+            let mut builder = LexerBuilder::new();
+            for spec in specs {
+                match actions.iter().find(|(tag, _)| *tag == spec.tag) {
+                    Some(&(_, action)) => { builder.push(&spec.pattern, action); },
+                    None => return Err(SpecError{ tag: spec.tag.clone() }),
+                }
+            }
+            Ok(builder)
+        }
+    }
+
+    impl<'s, TokenType, S: Sharing> LexerBuilder<'s, TokenType, S> {
+        /// Adds a new token to the LexerBuilder
+        ///
+        /// token is the regex representation of the string  
+        /// action is a function or closure converting the &str representation of the token to a Token
+        ///
+        /// The pattern is anchored at the current position automatically, so it
+        /// does not need a leading `^`, and alternations like `a|bc` are anchored
+        /// as a whole.
+        pub fn push<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::token(action)));
+            self
+        }
+
+        /// Adds a new token that keeps its matched text as a `String`
+        ///
+        /// `make` is usually a tuple variant, e.g. `push_str(r"[a-z]+", Token::Ident)`.
+        pub fn push_str<F: Fn(String) -> TokenType + 'static>(&mut self, token: &'s str, make: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::owned(make)));
+            self
+        }
+
+        /// Adds a new token converted from the matched text with `From<&str>`
+        ///
+        /// Useful while prototyping, before the rule has an action of its own.
+        pub fn push_pattern(&mut self, token: &'s str) -> &mut Self
+        where TokenType: for<'x> From<&'x str> + 'static,
+              S: Accepts<fn(&str) -> TokenType>,
+        {
+            self.push(token, (|x: &str| TokenType::from(x)) as fn(&str) -> TokenType)
+        }
+
+        /// Adds a new token with a pattern generated at runtime, see [push](LexerBuilder::push)
+        pub fn push_owned<F: Fn(&str) -> TokenType + 'static>(&mut self, token: String, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::token(action)));
+            self
+        }
+
+        /// Adds a single rule matching any of `patterns`, converted by the same action
+        ///
+        /// Each pattern is grouped on its own, so e.g. a `|` inside one of
+        /// them does not extend into the others. Unlike in an alternation the
+        /// longest match of any pattern wins within the rule, so e.g. `<=`
+        /// wins against `<` no matter which is listed first.
+        pub fn push_any<F: Fn(&str) -> TokenType + 'static>(&mut self, patterns: &[&str], action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            let token = patterns.iter().map(|p| format!("(?:{})", p)).collect::<Vec<_>>().join("|");
+            self.actions.push(LexAction{ longest: true, ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token matched with its own inline regex flags
+        ///
+        /// flags uses the syntax of the regex crate's inline flags, e.g. `"i"`
+        /// for a case insensitive rule or `"s"` to let `.` match `\n`.
+        pub fn push_with_flags<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &'s str, flags: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ flags: Some(flags), ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token that only matches if `follow` matches directly after it
+        ///
+        /// The input matched by `follow` is not consumed. This emulates a regex
+        /// lookahead like `\d+(?=\.\d)`, which the regex crate does not support.
+        pub fn push_with_lookahead<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &'s str, follow: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ follow: Some(follow), ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token with a display name used in diagnostics
+        ///
+        /// The name can be looked up by the index of the rule, e.g. from
+        /// [tok_with_rule](Lexer::tok_with_rule), with [rule_name](Lexer::rule_name).
+        pub fn push_named<F: Fn(&str) -> TokenType + 'static>(&mut self, name: &'s str, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ name: Some(name), ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token that wins ties on the length of the match by `priority`
+        ///
+        /// Rules pushed without a priority have priority 0, so e.g. a keyword
+        /// with priority 1 wins against an identifier rule matching the same
+        /// text, no matter which was pushed first. See [priority](LexAction::priority).
+        pub fn push_with_priority<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &'s str, priority: i32, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ priority, ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token that is only matched in the lexical mode `mode`
+        ///
+        /// Rules pushed without a mode are active in the default mode. Modes
+        /// are entered and left by rules pushed with
+        /// [push_transition](LexerBuilder::push_transition), or with
+        /// [push_mode](Lexer::push_mode) and [pop_mode](Lexer::pop_mode).
+        pub fn push_in_mode<F: Fn(&str) -> TokenType + 'static>(&mut self, mode: &'s str, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ mode: Some(mode), ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Adds a new token in `mode` that changes the lexical mode after it matched
+        ///
+        /// `mode` is the mode the rule is active in, None for the default mode.
+        /// The following tokens are lexed with the rules of the new mode.
+        pub fn push_transition<F: Fn(&str) -> TokenType + 'static>(&mut self, mode: Option<&'s str>, token: &'s str, change: ModeChange<'s>, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction{ mode, mode_change: Some(change), ..LexAction::new(token, S::token(action)) });
+            self
+        }
+
+        /// Creates a LexerBuilder from a list of LexActions, dropping duplicates
+        ///
+        /// A rule is dropped if an earlier rule has the same pattern, flags,
+        /// lookahead, [priority](LexAction::priority) and mode, as the earlier
+        /// rule always wins the tie. Rules following
+        /// a [Fallible](Action::Fallible) rule are kept, as it may decline a
+        /// match. The order of the remaining rules is preserved, so rule
+        /// indices refer to the remaining rules and shift down past each
+        /// dropped one. Every dropped rule is reported by
+        /// [validate](LexerBuilder::validate) with its index in `actions`,
+        /// shadowed by the index of the remaining rule.
+        pub fn from_actions<I: IntoIterator<Item = LexAction<'s, TokenType, S>>>(actions: I) -> Self {
+            let mut builder = Self::default();
+            for (rule, action) in actions.into_iter().enumerate() {
+                let duplicate = builder.actions.iter().position(|a| {
+                    a.token == action.token && a.flags == action.flags && a.follow == action.follow
+                        && a.priority == action.priority && a.mode == action.mode
+                        && !matches!(a.action.view(), ActionRef::Fallible(_))
+                });
+                match duplicate {
+                    Some(shadowed_by) => builder.dropped.push(Warning{ rule, pattern: action.token.to_string(), shadowed_by }),
+                    None => builder.actions.push(action),
+                }
+            }
+            builder
+        }
+
+        /// Adds a new token whose matched text is transformed before the action sees it
+        ///
+        /// Useful to share normalizations like case folding or stripping quotes
+        /// between rules.
+        pub fn push_with_preprocess<P, F>(&mut self, token: &'s str, preprocess: P, action: F) -> &mut Self
+        where P: Fn(&str) -> Cow<'_, str> + 'static,
+              F: Fn(&str) -> TokenType + 'static,
+              S: Accepts<(P, F)>,
+        {
+            self.actions.push(LexAction::new(token, S::preprocessed((preprocess, action))));
+            self
+        }
+
+        /// Adds many new tokens at once, see [push](LexerBuilder::push)
+        pub fn extend<I: IntoIterator<Item = (&'s str, fn(&str) -> TokenType)>>(&mut self, rules: I) -> &mut Self
+        where TokenType: 'static,
+              S: Accepts<fn(&str) -> TokenType>,
+        {
+            for (token, action) in rules {
+                self.push(token, action);
+            }
+            self
+        }
+
+        /// Adds a new token whose action may decline the match by returning `None`
+        ///
+        /// The Lexer then tries the next candidate, e.g. a shorter match of
+        /// another rule, see [Fallible](Action::Fallible).
+        pub fn push_fallible<F: Fn(&str) -> Option<TokenType> + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::fallible(action)));
+            self
+        }
+
+        /// Adds a new token whose action converts a match to several tokens
+        ///
+        /// See [Multi](Action::Multi).
+        pub fn push_multi<F: Fn(&str) -> Vec<TokenType> + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::multi(action)));
+            self
+        }
+
+        /// Adds a new token whose action may fail with an error
+        ///
+        /// See [Try](Action::Try).
+        pub fn push_try<F: Fn(&str) -> Result<TokenType, Box<dyn Error>> + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::try_token(action)));
+            self
+        }
+
+        /// Adds a new token whose action receives a reused scratch buffer
+        ///
+        /// See [Scratch](Action::Scratch).
+        pub fn push_scratch<F: Fn(&str, &mut String) -> TokenType + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::scratch(action)));
+            self
+        }
+
+        /// Adds a new token whose action also receives the input following the match
+        ///
+        /// See [Contextual](Action::Contextual).
+        ///
+        /// # Panics
+        ///
+        /// Lexing panics if the action consumes no input, or more than is left,
+        /// or does not end on a char boundary.
+        pub fn push_contextual<F: Fn(&str, &str) -> (TokenType, Option<usize>) + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::contextual(action)));
+            self
+        }
+
+        /// Adds a new token whose action receives the capture groups of the match
+        ///
+        /// Useful if a token consists of several parts, e.g. `(\d+):(\d+)`.
+        pub fn push_captures<F: Fn(&Captures) -> TokenType + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::captures(action)));
+            self
+        }
+
+        /// Adds a new token consisting of a match of `token` and the rest of its line
+        ///
+        /// The action receives the text between the match and the end of the
+        /// line or input, without leading spaces and tabs and without the line
+        /// break, e.g. `hello world` for `echo hello world` with the pattern
+        /// `echo`. The line break is left to the following token. The length of
+        /// the match of `token` decides the longest match, as for a
+        /// [Contextual](Action::Contextual) rule.
+        pub fn push_rest_of_line<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &'s str, action: F) -> &mut Self
+        where S: Accepts<F>,
+        {
+            self.actions.push(LexAction::new(token, S::rest_of_line(action)));
+            self
+        }
+
+        /// Adds a new rule whose matches are consumed without producing a token
+        ///
+        /// Useful for comments. Skip rules take part in the longest match like
+        /// any other rule.
+        pub fn push_skip(&mut self, token: &'s str) -> &mut Self {
+            self.actions.push(LexAction{ token: token.into(), action: S::Action::<TokenType>::skip(), flags: None, follow: None, priority: 0, name: None, mode: None, mode_change: None, longest: false });
+            self
+        }
+
+        /// Sets the whitespace skipped before a token when `skip_ws` is true
+        ///
+        /// Defaults to `\s+`.
+        pub fn whitespace(&mut self, token: &'s str) -> &mut Self {
+            self.whitespace = Some(token);
+            self
+        }
+
+        /// Adds a pattern that is skipped together with the whitespace when `skip_ws` is true
+        ///
+        /// The whitespace and all ignored patterns are skipped repeatedly
+        /// until none of them matches, so e.g. spaces, line comments and block
+        /// comments can follow each other in any order before a token.
+        pub fn ignore(&mut self, pattern: &'s str) -> &mut Self {
+            self.ignored.push(pattern);
+            self
+        }
+
+        /// Sets whether `\n` is left to the rules instead of being skipped as whitespace
+        ///
+        /// Useful for line oriented formats, where a rule like `\n` can then
+        /// produce a separator token. A `\r` in front of the `\n` is still
+        /// skipped, so the rules see `\r\n` line endings as `\n` too. Only
+        /// changes the default whitespace, not one set with
+        /// [whitespace](LexerBuilder::whitespace).
+        pub fn significant_newlines(&mut self, yes: bool) -> &mut Self {
+            self.significant_newlines = yes;
+            self
+        }
+
+        /// Sets whether all patterns, including the whitespace, are matched case insensitively
+        pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+            self.case_insensitive = yes;
+            self
+        }
+
+        /// Sets whether `.` matches `\n` in all patterns, like the `s` flag
+        pub fn dot_matches_newline(&mut self, yes: bool) -> &mut Self {
+            self.dot_matches_newline = yes;
+            self
+        }
+
+        /// Sets whether `^` and `$` match at line boundaries in all patterns, like the `m` flag
+        ///
+        /// Patterns are always anchored at the current position, not by an
+        /// added `^`, so this only changes `^` and `$` written in a pattern. With
+        /// it `^` matches after every `\n`, not just at the start of input.
+        pub fn multi_line(&mut self, yes: bool) -> &mut Self {
+            self.multi_line = yes;
+            self
+        }
+
+        /// Sets a token that is returned exactly once when the end of input is reached
+        ///
+        /// Afterwards the Lexer returns `None` as usual.
+        pub fn eof_token(&mut self, make: fn() -> TokenType) -> &mut Self {
+            self.eof_token = Some(make);
+            self
+        }
+
+        /// Sets a function called whenever several rules tie for the longest match
+        ///
+        /// It receives the indices of the tied rules in insertion order and the
+        /// matched text. Ties are still resolved in favour of the first rule.
+        /// Rules losing the tie by their [priority](LexAction::priority) are
+        /// not considered tied.
+        pub fn on_ambiguity(&mut self, f: fn(&[usize], &str)) -> &mut Self {
+            self.on_ambiguity = Some(f);
+            self
+        }
+
+        /// Sets a token that is returned for each char no rule matches
+        ///
+        /// The function receives the offending char. Instead of failing with a
+        /// [LexError] of kind [Unmatched](LexErrorKind::Unmatched), the Lexer
+        /// returns this token and continues after the char, so all input is
+        /// covered by tokens.
+        pub fn error_token(&mut self, make: fn(&str) -> TokenType) -> &mut Self {
+            self.error_token = Some(make);
+            self
+        }
+
+        /// Sets a function producing tokens in front of the first token of each line
+        ///
+        /// It receives the indentation of the line, i.e. the number of chars in
+        /// front of the token, and a stack of indentation levels kept by the
+        /// Lexer for it, which is empty at the start of input. At the end of
+        /// input it is called once more with an indentation of 0, so e.g.
+        /// Python like INDENT and DEDENT tokens can be produced. The tokens
+        /// have an empty span at the start of the following token. The
+        /// function is called once per line and its tokens are queued, like
+        /// those of a [Multi](Action::Multi) action, so it should only depend
+        /// on its arguments. Input skipped by [push_skip](LexerBuilder::push_skip)
+        /// rules, e.g. a comment, is not a token, so a line holding nothing
+        /// else produces no tokens.
+        pub fn on_line_start(&mut self, f: LineStartHook<TokenType>) -> &mut Self {
+            self.on_line_start = Some(f);
+            self
+        }
+
+        /// Limits the number of bytes a single match may consume
+        ///
+        /// If the match chosen by the [match_strategy](LexerBuilder::match_strategy)
+        /// is longer, lexing fails with a [LexError] of kind
+        /// [TooLong](LexErrorKind::TooLong) instead, without running the
+        /// action of the match. Matches of [Skip](Action::Skip) rules, e.g.
+        /// long comments, are not limited. Actions that decide on the token
+        /// themselves run before the check: a [Fallible](Action::Fallible)
+        /// match may be declined, the length of a
+        /// [Contextual](Action::Contextual) token is the one its action
+        /// returned, and [Multi](Action::Multi) matches producing no tokens are
+        /// not limited.
+        pub fn max_token_len(&mut self, n: usize) -> &mut Self {
+            self.max_token_len = Some(n);
+            self
+        }
+
+        /// Limits the approximate size of each compiled regex to `bytes`
+        ///
+        /// Building fails with [CompiledTooBig](regex::Error::CompiledTooBig)
+        /// for a pattern exceeding it, instead of using a lot of memory for
+        /// e.g. `[a-z]{1000}` from untrusted configuration. Defaults to the limit
+        /// of the regex crate. Run time is linear in the input anyway, see
+        /// [max_token_len](LexerBuilder::max_token_len) to also bound the
+        /// length of a single token.
+        pub fn size_limit(&mut self, bytes: usize) -> &mut Self {
+            self.size_limit = Some(bytes);
+            self
+        }
+
+        /// Limits the approximate size of the cache of the lazy DFA of each regex to `bytes`
+        ///
+        /// Unlike [size_limit](LexerBuilder::size_limit) this never makes
+        /// building fail, a regex whose cache would not fit uses a slower
+        /// engine instead. Defaults to the limit of the regex crate.
+        pub fn dfa_size_limit(&mut self, bytes: usize) -> &mut Self {
+            self.dfa_size_limit = Some(bytes);
+            self
+        }
+
+        /// Sets how the rule producing a token is chosen
+        ///
+        /// Defaults to [Longest](Strategy::Longest). With
+        /// [First](Strategy::First) the [on_ambiguity](LexerBuilder::on_ambiguity)
+        /// function is never called.
+        pub fn match_strategy(&mut self, strategy: Strategy) -> &mut Self {
+            self.match_strategy = strategy;
+            self
+        }
+
+        /// Sets whether [next_token](Lexer::next_token) skips whitespace
+        ///
+        /// Defaults to false.
+        pub fn default_skip_ws(&mut self, yes: bool) -> &mut Self {
+            self.default_skip_ws = yes;
+            self
+        }
+
+        /// Returns the number of rules added so far
+        ///
+        /// The whitespace and ignore patterns are not counted.
+        pub fn rule_count(&self) -> usize {
+            self.actions.len()
+        }
+
+        /// Builds a new Lexer from the Actions configured in the Builder
+        ///
+        /// The resulting Lexer picks the longest match, unless configured otherwise
+        /// with [match_strategy](LexerBuilder::match_strategy). If several rules
+        /// match the same length, the one pushed first wins.
+        ///
+        /// # Panics
+        ///
+        /// Panics if any pattern is not a valid regex or if no rule has been
+        /// added, see [try_build](LexerBuilder::try_build).
+        pub fn build<'a>(&self) -> Lexer<'a, TokenType, S>{
+            self.try_build().unwrap()
+        }
+
+        /// Builds a new Lexer, or returns an error naming the first invalid pattern
+        ///
+        /// The regexes are only compiled again if the rules or options they
+        /// depend on changed since the last build of this builder, so building
+        /// the same Lexer repeatedly is cheap. Lexers built from the same
+        /// regexes share them.
+        ///
+        /// A Lexer without rules could never produce a token, so building one
+        /// fails with [NoRules](BuildErrorKind::NoRules). Neither could a rule
+        /// that only produces empty matches, like `|abc` where the empty
+        /// alternative wins, which fails with
+        /// [EmptyMatch](BuildErrorKind::EmptyMatch). Rules like `\d*` are
+        /// kept, [tok](Lexer::tok) ignores their empty matches.
+        pub fn try_build<'a>(&self) -> Result<Lexer<'a, TokenType, S>, BuildError>{
+            if self.actions.is_empty() {
+                return Err(BuildError::no_rules());
+            }
+
+            let key = self.compile_key();
+            let mut last = self.cache.0.lock().unwrap_or_else(PoisonError::into_inner);
+            let compiled = match &*last {
+                Some((last_key, compiled)) if *last_key == key => compiled.clone(),
+                _ => {
+                    let compiled = Arc::new(self.compile()?);
+                    *last = Some((key, compiled.clone()));
+                    compiled
+                },
+            };
+            drop(last);
+
+            Ok(Lexer{
+                matched: PatternSet::new(compiled.rule_set.pattern_len()),
+                compiled,
+                actions: self.actions.iter().map(|a| a.action.clone()).collect(),
+                priorities: self.actions.iter().map(|a| a.priority).collect(),
+                names: self.actions.iter().map(|a| a.name.map(String::from)).collect(),
+                enabled: vec![true; self.actions.len()],
+                modes: self.actions.iter().map(|a| a.mode.map(String::from)).collect(),
+                transitions: self.actions.iter().map(|a| a.mode_change.map(|change| match change {
+                    ModeChange::Push(mode) => Transition::Push(String::from(mode)),
+                    ModeChange::Pop => Transition::Pop,
+                })).collect(),
+                scratch: Mutex::new(String::new()),
+                data: Cow::Borrowed(""),
+                start: 0,
+                end: 0,
+                curr_pos: 0,
+                line: 1,
+                column: 1,
+                last_span: None,
+                last_rule: None,
+                before_last: None,
+                eof_token: self.eof_token,
+                on_ambiguity: self.on_ambiguity,
+                error_token: self.error_token,
+                on_line_start: self.on_line_start,
+                max_token_len: self.max_token_len,
+                match_strategy: self.match_strategy,
+                skip_ws: self.default_skip_ws,
+                eof_done: false,
+                expansion: None,
+                indents: Vec::new(),
+                line_started: 0,
+                line_tokens: None,
+                queued: None,
+                mode_stack: Vec::new(),
+                streaming: false,
+                lookahead: VecDeque::new(),
+                last_error: None,
+            })
+        }
+
+        /// Looks for rules that can never produce a token
+        ///
+        /// This is a heuristic, it finds rules with the same pattern as an
+        /// earlier rule, and literal rules like `if` that an earlier rule like
+        /// `[a-z]+` matches completely, as ties are won by the earlier rule.
+        /// Rules with a higher [priority](LexAction::priority) are treated like
+        /// earlier rules, rules with a lower priority like later ones. Rules whose action may decline or resize the match, or with
+        /// a lookahead, are not considered to shadow anything. Invalid patterns
+        /// are ignored, they are reported by [try_build](LexerBuilder::try_build).
+        /// The rules [dropped](LexerBuilder::dropped) by
+        /// [from_actions](LexerBuilder::from_actions) come first.
+        pub fn validate(&self) -> Vec<Warning> {
+            let syntax = self.syntax();
+            let regexes: Vec<_> = self.actions.iter()
+                .map(|a| {
+                    let config = if a.longest { self.config().match_kind(MatchKind::All) } else { self.config() };
+                    meta::Regex::builder().configure(config).syntax(syntax).build(&pattern(&a.token, a.flags)).ok()
+                })
+                .collect();
+
+            let mut warnings = self.dropped.clone();
+            for (rule, later) in self.actions.iter().enumerate() {
+                let literal = later.flags.is_none() && !self.case_insensitive && regex::escape(&later.token) == later.token;
+                let shadowed_by = self.actions.iter().enumerate().position(|(earlier, a)| {
+                    let wins = a.priority > later.priority || (a.priority == later.priority && earlier < rule);
+                    if !wins || a.mode != later.mode || a.follow.is_some() || matches!(a.action.view(), ActionRef::Fallible(_) | ActionRef::Contextual(_)) {
+                        return false;
+                    }
+                    if a.token == later.token && a.flags == later.flags {
+                        return true;
+                    }
+                    literal && !later.token.is_empty() && regexes[earlier].as_ref()
+                        .and_then(|regex| match_at(regex, &later.token, 0, later.token.len())) == Some(later.token.len())
+                });
+                if let Some(shadowed_by) = shadowed_by {
+                    warnings.push(Warning{ rule, pattern: later.token.to_string(), shadowed_by });
+                }
+            }
+            warnings
+        }
+
+        /// Returns everything the compiled regexes depend on
+        fn compile_key(&self) -> CompileKey {
+            CompileKey{
+                rules: self.actions.iter().map(|a| (
+                    a.token.to_string(),
+                    a.flags.map(String::from),
+                    a.follow.map(String::from),
+                    matches!(a.action.view(), ActionRef::Captures(_)),
+                    a.longest,
+                )).collect(),
+                whitespace: self.whitespace.map(String::from),
+                ignored: self.ignored.iter().map(|&i| String::from(i)).collect(),
+                significant_newlines: self.significant_newlines,
+                case_insensitive: self.case_insensitive,
+                dot_matches_newline: self.dot_matches_newline,
+                multi_line: self.multi_line,
+                size_limit: self.size_limit,
+                dfa_size_limit: self.dfa_size_limit,
+            }
+        }
+
+        /// Returns the limits all regexes are compiled with
+        fn config(&self) -> meta::Config {
+            let mut config = meta::Config::new();
+            if let Some(limit) = self.size_limit {
+                config = config.nfa_size_limit(Some(limit));
+            }
+            if let Some(limit) = self.dfa_size_limit {
+                config = config.hybrid_cache_capacity(limit);
+            }
+            config
+        }
+
+        /// Returns the syntax options all patterns are compiled with
+        fn syntax(&self) -> syntax::Config {
+            syntax::Config::new()
+                .case_insensitive(self.case_insensitive)
+                .dot_matches_new_line(self.dot_matches_newline)
+                .multi_line(self.multi_line)
+        }
+
+        /// Compiles the regexes of all rules
+        fn compile(&self) -> Result<Compiled, BuildError> {
+            const WS_PATTERN: &str = r"\s+";
+            const WS_NO_NEWLINE_PATTERN: &str = r"[^\S\n]+";
+            lazy_static! {
+                static ref WS: meta::Regex = meta::Regex::new(WS_PATTERN).unwrap();
+                static ref WS_NO_NEWLINE: meta::Regex = meta::Regex::new(WS_NO_NEWLINE_PATTERN).unwrap();
+            }
+
+            let syntax = self.syntax();
+            let config = self.config();
+            let compile = |token: &str, flags: Option<&str>| {
+                meta::Regex::builder()
+                    .configure(config.clone())
+                    .syntax(syntax)
+                    .build(&pattern(token, flags))
+                    .map_err(|e| BuildError::from_meta(token, e))
+            };
+
+            let whitespace = if self.ignored.is_empty() {
+                match self.whitespace {
+                    Some(ws) => compile(ws, None)?,
+                    None if self.significant_newlines => WS_NO_NEWLINE.clone(),
+                    None => WS.clone(),
+                }
+            } else {
+                let ws = match self.whitespace {
+                    Some(ws) => ws,
+                    None if self.significant_newlines => WS_NO_NEWLINE_PATTERN,
+                    None => WS_PATTERN,
+                };
+                // Name the offending pattern if one is invalid
+                for &p in std::iter::once(&ws).chain(&self.ignored) {
+                    compile(p, None)?;
+                }
+                let any = std::iter::once(&ws).chain(&self.ignored).map(|p| format!("(?:{})", p)).collect::<Vec<_>>().join("|");
+                compile(&format!("(?:{})+", any), None)?
+            };
+            // A pattern like `a)|(b` only becomes valid when wrapped in the
+            // flags of its rule, so such rules are also checked on their own
+            for (rule, a) in self.actions.iter().enumerate().filter(|(_, a)| a.flags.is_some()) {
+                syntax::parse_with(&a.token, &syntax).map_err(|e| BuildError{
+                    pattern: a.token.to_string(),
+                    rule: Some(rule),
+                    kind: BuildErrorKind::Regex(regex::Error::Syntax(e.to_string())),
+                })?;
+            }
+
+            let follows = self.actions.iter().enumerate()
+                .map(|(rule, a)| a.follow.map(|f| compile(f, a.flags).map_err(|e| BuildError{ rule: Some(rule), ..e })).transpose())
+                .collect::<Result<Vec<_>, _>>()?;
+            let captures = self.actions.iter().enumerate().map(|(rule, a)| match a.action.view() {
+                ActionRef::Captures(_) => {
+                    let mut builder = RegexBuilder::new(&pattern(&a.token, a.flags));
+                    builder.case_insensitive(self.case_insensitive)
+                        .dot_matches_new_line(self.dot_matches_newline)
+                        .multi_line(self.multi_line);
+                    if let Some(limit) = self.size_limit {
+                        builder.size_limit(limit);
+                    }
+                    if let Some(limit) = self.dfa_size_limit {
+                        builder.dfa_size_limit(limit);
+                    }
+                    builder.build()
+                        .map(Some)
+                        .map_err(|error| BuildError{ pattern: a.token.to_string(), rule: Some(rule), kind: BuildErrorKind::Regex(error) })
+                },
+                _ => Ok(None),
+            }).collect::<Result<Vec<_>, _>>()?;
+
+            let patterns: Vec<_> = self.actions.iter().map(|a| pattern(&a.token, a.flags)).collect();
+            let rule_set = rule_set_builder(&config, syntax)
+                .build_many(&patterns)
+                .map_err(|e| {
+                    // Name the offending rule if it is invalid on its own
+                    match self.actions.iter().enumerate().find_map(|(rule, a)| compile(&a.token, a.flags).err().map(|e| (rule, e))) {
+                        Some((rule, error)) => BuildError{ rule: Some(rule), ..error },
+                        None => BuildError::from_meta(&patterns.join("|"), e),
+                    }
+                })?;
+            for (rule, (a, p)) in self.actions.iter().zip(&patterns).enumerate() {
+                check_non_empty(rule, &a.token, p, a.longest, &syntax)?;
+            }
+
+            Ok(Compiled{
+                whitespace,
+                rules: vec![OnceLock::new(); patterns.len()],
+                longest: self.actions.iter().map(|a| a.longest).collect(),
+                rule_set,
+                patterns,
+                sources: self.actions.iter().map(|a| a.token.to_string()).collect(),
+                syntax,
+                config,
+                captures,
+                follows,
+            })
+        }
+    }
+
+    impl<'a, TokenType, S: Sharing> Lexer<'a, TokenType, S> {
+        /// Resets the parser to the starting state with input data
+        pub fn init(&mut self, data: String){
+            self.init_cow(Cow::Owned(data));
+        }
+
+        /// Resets the parser to the starting state with borrowed input data
+        ///
+        /// Unlike [init](Lexer::init) the data is not copied.
+        pub fn init_str(&mut self, data: &'a str){
+            self.init_cow(Cow::Borrowed(data));
+        }
+
+        /// Resets the parser to the start of `range` within the input data
+        ///
+        /// No token is lexed past the end of `range`, the end of the range is
+        /// treated as the end of input. Positions, lines and columns are still
+        /// counted from the start of the data, so all spans and error positions
+        /// index into the whole data. Subtract the start of
+        /// [range](Lexer::range) for positions relative to the range.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `range` is out of bounds or not on char boundaries.
+        pub fn init_range(&mut self, data: String, range: Range<usize>){
+            self.init_cow_range(Cow::Owned(data), range);
+        }
+
+        /// Like [init_range](Lexer::init_range), but with borrowed input data
+        pub fn init_str_range(&mut self, data: &'a str, range: Range<usize>){
+            self.init_cow_range(Cow::Borrowed(data), range);
+        }
+
+        fn init_cow(&mut self, data: Cow<'a, str>){
+            let len = data.len();
+            self.init_cow_range(data, 0..len);
+        }
+
+        fn init_cow_range(&mut self, data: Cow<'a, str>, range: Range<usize>){
+            assert!(data.get(range.clone()).is_some(), "invalid range {:?} of input with length {}", range, data.len());
+            self.data = data;
+            self.start = range.start;
+            self.end = range.end;
+            self.streaming = false;
+            self.reset();
+        }
+
+        /// Appends more input data, e.g. the next chunk read from a file or socket
+        ///
+        /// Until [finish](Lexer::finish) is called, the Lexer no longer assumes
+        /// that the end of the data is the end of input. Instead of returning a
+        /// token that might continue in the next chunk, or reporting the end of
+        /// input, it returns a [LexError] of kind
+        /// [Incomplete](LexErrorKind::Incomplete) without consuming the token.
+        /// This also holds if no rule matches, as one might match once more
+        /// input has been fed.
+        ///
+        /// A [range](Lexer::range) reaching to the end of the data grows with
+        /// the fed input.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the Lexer lexes a range ending before the end of the
+        /// data, as the fed input would not be part of the range.
+        pub fn feed(&mut self, more: &str){
+            assert!(self.end == self.data.len(), "can not feed input to the range {:?} of input with length {}", self.start..self.end, self.data.len());
+            self.end += more.len();
+            self.data.to_mut().push_str(more);
+            self.streaming = true;
+        }
+
+        /// Marks the end of the input fed with [feed](Lexer::feed)
+        pub fn finish(&mut self){
+            self.streaming = false;
+        }
+
+        /// Resets the parser to the start of the current input data
+        pub fn reset(&mut self){
+            self.curr_pos = 0;
+            self.line = 1;
+            self.column = 1;
+            self.last_span = None;
+            self.last_rule = None;
+            self.before_last = None;
+            self.eof_done = false;
+            self.expansion = None;
+            self.indents.clear();
+            self.line_started = 0;
+            self.line_tokens = None;
+            self.queued = None;
+            self.mode_stack.clear();
+            self.lookahead.clear();
+            self.last_error = None;
+            self.advance_to(self.start);
+        }
+
+        /// Returns the current position, to return to it later with [restore](Lexer::restore)
+        ///
+        /// Unlike [seek](Lexer::seek) this also keeps the state of the most
+        /// recent token, e.g. for [unput](Lexer::unput).
+        pub fn checkpoint(&self) -> Checkpoint {
+            Checkpoint{
+                state: self.state(),
+                last_span: self.last_span.clone(),
+                last_rule: self.last_rule,
+                before_last: self.before_last.clone(),
+            }
+        }
+
+        /// Returns to a position saved with [checkpoint](Lexer::checkpoint)
+        ///
+        /// The checkpoint must have been created by this Lexer for its current input.
+        pub fn restore(&mut self, s: Checkpoint) {
+            self.lookahead.clear();
+            self.load(s);
+        }
+
+        /// Like [restore](Lexer::restore), but keeps the tokens lexed ahead
+        fn load(&mut self, s: Checkpoint) {
+            self.load_state(s.state);
+            self.last_span = s.last_span;
+            self.last_rule = s.last_rule;
+            self.before_last = s.before_last;
+        }
+
+        /// Returns the position dependent state apart from the most recent token
+        fn state(&self) -> State {
+            State{
+                curr_pos: self.curr_pos,
+                line: self.line,
+                column: self.column,
+                eof_done: self.eof_done,
+                expansion: self.expansion.clone(),
+                indents: self.indents.clone(),
+                line_started: self.line_started,
+                line_tokens: self.line_tokens.clone(),
+                mode_stack: self.mode_stack.clone(),
+            }
+        }
+
+        /// Moves the Lexer to `s`, keeping the most recent token
+        fn load_state(&mut self, s: State) {
+            self.curr_pos = s.curr_pos;
+            self.line = s.line;
+            self.column = s.column;
+            self.eof_done = s.eof_done;
+            self.expansion = s.expansion;
+            self.indents = s.indents;
+            self.line_started = s.line_started;
+            self.line_tokens = s.line_tokens;
+            self.mode_stack = s.mode_stack;
+        }
+
+        /// Returns the whole input data
+        pub fn input(&self) -> &str {
+            &self.data
+        }
+
+        /// Returns the input that has not been lexed yet
+        pub fn remaining(&self) -> &str {
+            &self.data[self.curr_pos..self.end]
+        }
+
+        /// Returns the input that already has been lexed
+        pub fn consumed(&self) -> &str {
+            &self.data[self.start..self.curr_pos]
+        }
+
+        /// Returns the source text of the most recently produced token
+        ///
+        /// Returns None if no token has been produced yet, or if it has been
+        /// returned with [unput](Lexer::unput).
+        pub fn last_match(&self) -> Option<&str> {
+            self.last_span.clone().map(|span| &self.data[span])
+        }
+
+        /// Adds a new token to the Lexer, see [LexerBuilder::push]
+        ///
+        /// Only the new pattern and the rule set are compiled, the other rules
+        /// are kept. The pattern is compiled with the options of the
+        /// LexerBuilder, and the current position is kept as well. Like any rule
+        /// added last, it loses ties against all other rules. The rule is
+        /// checked like by [try_build](LexerBuilder::try_build), an error names
+        /// the index the rule would have had.
+        pub fn add_rule<F: Fn(&str) -> TokenType + 'static>(&mut self, token: &str, action: F) -> Result<(), BuildError>
+        where S: Accepts<F>,
+        {
+            let syntax = self.compiled.syntax;
+            let rule = self.actions.len();
+            let regex = meta::Regex::builder()
+                .configure(self.compiled.config.clone())
+                .syntax(syntax)
+                .build(token)
+                .map_err(|e| BuildError{ rule: Some(rule), ..BuildError::from_meta(token, e) })?;
+            check_non_empty(rule, token, token, false, &syntax)?;
+            let mut patterns = self.compiled.patterns.clone();
+            patterns.push(String::from(token));
+            let rule_set = rule_set_builder(&self.compiled.config, syntax)
+                .build_many(&patterns)
+                .map_err(|e| BuildError{ rule: Some(rule), ..BuildError::from_meta(token, e) })?;
+
+            let compiled = Arc::make_mut(&mut self.compiled);
+            compiled.rule_set = rule_set;
+            compiled.rules.push(OnceLock::from(regex));
+            compiled.longest.push(false);
+            compiled.patterns = patterns;
+            compiled.sources.push(String::from(token));
+            compiled.captures.push(None);
+            compiled.follows.push(None);
+            self.matched = PatternSet::new(compiled.rule_set.pattern_len());
+            self.actions.push(S::token(action));
+            self.priorities.push(0);
+            self.names.push(None);
+            self.enabled.push(true);
+            self.modes.push(None);
+            self.transitions.push(None);
+            // Tokens lexed ahead might be lexed differently with the new rule
+            self.lookahead.clear();
+            Ok(())
+        }
+
+        /// Returns a Lexer with the same rules and configuration, but without input
+        ///
+        /// The compiled regexes are shared, so this is cheap. Unlike
+        /// [clone](Clone::clone) the input and position are not copied, the
+        /// new Lexer has to be [init](Lexer::init)ed, e.g. by a worker thread
+        /// it has been sent to.
+        pub fn clone_fresh<'b>(&self) -> Lexer<'b, TokenType, S> {
+            let mut lexer = self.with_data(Cow::Borrowed(""));
+            lexer.init_cow(Cow::Borrowed(""));
+            lexer
+        }
+
+        /// Moves the regexes, actions and input out of the Lexer
+        ///
+        /// This allows driving the matching by hand, see [LexerParts] for how
+        /// the parts fit together. The state of the most recent token and any
+        /// tokens lexed ahead are dropped.
+        pub fn into_parts(self) -> LexerParts<'a, TokenType, S> {
+            let rules = (0..self.compiled.rules.len()).map(|i| self.rule(i).clone()).collect();
+            let compiled = Arc::unwrap_or_clone(self.compiled);
+            LexerParts{
+                rule_set: compiled.rule_set,
+                rules,
+                follows: compiled.follows,
+                captures: compiled.captures,
+                actions: self.actions,
+                priorities: self.priorities,
+                enabled: self.enabled,
+                whitespace: compiled.whitespace,
+                data: self.data,
+                position: self.curr_pos,
+                end: self.end,
+            }
+        }
+
+        /// Returns the display name of rule `index`
+        ///
+        /// Returns None for rules pushed without a name, see
+        /// [push_named](LexerBuilder::push_named), and for indices past the
+        /// last rule, like the one of the EOF token.
+        pub fn rule_name(&self, index: usize) -> Option<&str> {
+            self.names.get(index)?.as_deref()
+        }
+
+        /// Returns the number of rules, see [rule_count](LexerBuilder::rule_count)
+        pub fn rule_count(&self) -> usize {
+            self.actions.len()
+        }
+
+        /// Returns the patterns of all rules in insertion order
+        pub fn patterns(&self) -> Vec<&str> {
+            self.compiled.sources.iter().map(String::as_str).collect()
+        }
+
+        /// Returns the current 1-based line and column
+        ///
+        /// Columns are counted in chars, not bytes.
+        pub fn position(&self) -> (usize, usize) {
+            (self.line, self.column)
+        }
+
+        /// Converts a byte offset into the input to a 1-based line and column
+        ///
+        /// Columns are counted in chars, like in [position](Lexer::position).
+        /// An offset past the end of input is treated as the end of input, one
+        /// inside of a char as the start of that char.
+        pub fn line_col(&self, offset: usize) -> (usize, usize) {
+            let mut offset = offset.min(self.data.len());
+            while !self.data.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            advance_line_col(&self.data[..offset], (1, 1))
+        }
+
+        /// Returns the byte offset of the start of the line containing the current position
+        pub fn line_start(&self) -> usize {
+            self.data[..self.curr_pos].rfind('\n').map_or(0, |i| i + 1)
+        }
+
+        /// Returns the line containing the current position, without the line break
+        ///
+        /// The line break is `\n` or `\r\n`. Lines are taken from the whole input, also for input set with
+        /// [init_range](Lexer::init_range).
+        pub fn current_line(&self) -> &str {
+            let start = self.line_start();
+            let end = self.data[self.curr_pos..].find('\n').map_or(self.data.len(), |i| self.curr_pos + i);
+            let line = &self.data[start..end];
+            line.strip_suffix('\r').unwrap_or(line)
+        }
+
+        /// Returns the byte range of the input that is lexed
+        ///
+        /// This is the range passed to [init_range](Lexer::init_range), or the
+        /// whole input.
+        pub fn range(&self) -> Range<usize> {
+            self.start..self.end
+        }
+
+        /// Returns the length of the input in bytes
+        ///
+        /// For input set with [init_range](Lexer::init_range) this is the
+        /// length of the range.
+        pub fn len(&self) -> usize {
+            self.end - self.start
+        }
+
+        /// Returns true if the input is empty
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        /// Returns the fraction of the input that already has been lexed, from 0.0 to 1.0
+        ///
+        /// Empty input counts as completely lexed.
+        pub fn progress(&self) -> f64 {
+            match self.len() {
+                0 => 1.0,
+                len => (self.curr_pos - self.start) as f64 / len as f64,
+            }
+        }
+
+        /// Returns the current byte offset into the input
+        ///
+        /// See [position](Lexer::position) for the line and column.
+        pub fn offset(&self) -> usize {
+            self.curr_pos
+        }
+
+        /// Moves the Lexer to the byte offset `pos`
+        ///
+        /// Fails if `pos` is outside of the input or not on a char boundary.
+        pub fn seek(&mut self, pos: usize) -> Result<(), SeekError> {
+            if pos < self.start || pos > self.end || !self.data.is_char_boundary(pos) {
+                return Err(SeekError{ position: pos });
+            }
+            self.lookahead.clear();
+            self.expansion = None;
+            self.queued = None;
+            if pos < self.curr_pos {
+                self.reset();
+            }
+            self.advance_to(pos);
+            Ok(())
+        }
+
+        /// Skips the input up to and including the next match of `pattern`
+        ///
+        /// Skips to the end of input if `pattern` does not match. Returns the
+        /// number of bytes skipped. Useful to resynchronize after an error,
+        /// e.g. with `skip_until(";")`.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `pattern` is not a valid regex.
+        pub fn skip_until(&mut self, pattern: &str) -> usize {
+            let regex = meta::Regex::builder().syntax(self.compiled.syntax).build(pattern).unwrap();
+            let start = self.curr_pos;
+            let end = regex.search(&Input::new(&*self.data).range(start..self.end)).map_or(self.end, |m| m.end());
+            self.seek(end).unwrap();
+            end - start
+        }
+
+        /// Moves the Lexer back to the byte offset `to`
+        ///
+        /// Usually `to` is the start of a span returned by
+        /// [tok_spanned](Lexer::tok_spanned). A span never includes the
+        /// whitespace skipped before it, so that whitespace is skipped again by
+        /// the next call to `tok(true)`.
+        ///
+        /// If `to` is the start of the most recently produced token, the
+        /// lexical modes and the state of the line start hook are restored to
+        /// the ones in front of it. Otherwise they are reset like by
+        /// [seek](Lexer::seek), use a [checkpoint](Lexer::checkpoint) to keep them.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `to` is after the current position or not on a char boundary.
+        pub fn backtrack(&mut self, to: usize) {
+            assert!(to <= self.curr_pos, "can not backtrack forward to byte {}", to);
+            if self.last_span.as_ref().is_some_and(|span| span.start == to) {
+                if let Some(before) = self.before_last.take() {
+                    self.lookahead.clear();
+                    self.load_state(before);
+                    self.last_span = None;
+                    self.last_rule = None;
+                    // The state was saved in front of the skipped whitespace
+                    if self.curr_pos < to {
+                        self.advance_to(to);
+                    }
+                    return;
+                }
+            }
+            self.seek(to).unwrap();
+        }
+
+        /// Returns the most recently produced token to the input
+        ///
+        /// The Lexer is moved back to the start of that token, so the next call
+        /// to [tok](Lexer::tok) lexes it again. The lexical modes are restored
+        /// to the ones in front of the token. Returns false if there is no
+        /// token to return, e.g. because it already has been returned.
+        pub fn unput(&mut self) -> bool {
+            match self.last_span.clone() {
+                Some(span) => {
+                    self.backtrack(span.start);
+                    self.last_span = None;
+                    true
+                },
+                None => false,
+            }
+        }
+
+        /// Creates a LexError of `kind` for the input at the byte offset `position`
+        fn error(&self, kind: LexErrorKind, position: usize) -> LexError {
+            let (line, column) = if position >= self.curr_pos {
+                advance_line_col(&self.data[self.curr_pos..position], (self.line, self.column))
+            } else {
+                self.line_col(position)
+            };
+            let snippet = self.data[position..self.end].chars().take(SNIPPET_LEN).collect();
+            LexError{ kind, position, line, column, snippet, message: None }
+        }
+
+        /// Moves the Lexer forward to `pos`, updating line and column
+        fn advance_to(&mut self, pos: usize) {
+            (self.line, self.column) = advance_line_col(&self.data[self.curr_pos..pos], (self.line, self.column));
+            self.curr_pos = pos;
+        }
+
+        /// Returns the next Token, skipping whitespace as configured by
+        /// [default_skip_ws](LexerBuilder::default_skip_ws)
+        pub fn next_token(&mut self) -> Option<TokenType> {
+            self.tok(self.skip_ws)
+        }
+
+        /// Returns the next Token, or None if no token is found
+        pub fn tok(&mut self, skip_ws: bool) -> Option<TokenType> {
+            self.tok_spanned(skip_ws).map(|(token, _)| token)
+        }
+
+        /// Returns the next Token together with the input skipped before it
+        ///
+        /// The trivia is the whitespace and the matches of [Skip](Action::Skip)
+        /// rules, e.g. comments, between the previous token and this one, so
+        /// concatenating the trivia and the matched text of all tokens gives
+        /// back the input. If no token is left, the Lexer stays in front of the
+        /// trailing trivia, which [remaining](Lexer::remaining) still returns.
+        pub fn tok_with_trivia(&mut self) -> Option<(String, TokenType)> {
+            let before = self.checkpoint();
+            match self.tok_spanned(true) {
+                Some((token, span)) => {
+                    // Further tokens of a Multi match share its span and have no trivia
+                    let start = before.state.curr_pos.min(span.start);
+                    Some((self.data[start..span.start].to_string(), token))
+                },
+                None => {
+                    self.restore(before);
+                    None
+                },
+            }
+        }
+
+        /// Returns the next Token together with the index of the rule that produced it
+        ///
+        /// Rules are numbered in the order they were added to the
+        /// [LexerBuilder]. The [EOF token](LexerBuilder::eof_token) and the
+        /// [error token](LexerBuilder::error_token) and the tokens of the
+        /// [line start hook](LexerBuilder::on_line_start) are returned with
+        /// the index one past the last rule. See [rule_name](Lexer::rule_name) for
+        /// diagnostics.
+        pub fn tok_with_rule(&mut self, skip_ws: bool) -> Option<(TokenType, usize)> {
+            let token = self.tok(skip_ws)?;
+            Some((token, self.last_rule.unwrap_or(self.compiled.rules.len())))
+        }
+
+        /// Returns the next Token together with the byte range of input it was lexed from
+        pub fn tok_spanned(&mut self, skip_ws: bool) -> Option<(TokenType, Range<usize>)> {
+            self.try_tok_spanned(skip_ws).unwrap_or(None)
+        }
+
+        /// Returns the next Token
+        ///
+        /// Returns `Ok(None)` if the end of input has been reached, and an
+        /// error if the remaining input does not start with any token.
+        ///
+        /// With `skip_ws` the whitespace is skipped before any rule is tried,
+        /// so it is never part of a token, even if a rule like `\s*=` could
+        /// match it. Without it such a rule matches the whitespace as well.
+        ///
+        /// Matches of length zero never produce a token, if a rule can only
+        /// match the empty string at the current position it is ignored.
+        pub fn try_tok(&mut self, skip_ws: bool) -> Result<Option<TokenType>, LexError> {
+            Ok(self.try_tok_spanned(skip_ws)?.map(|(token, _)| token))
+        }
+
+        /// Returns the next Token together with the byte range of input it was lexed from
+        ///
+        /// See [try_tok](Lexer::try_tok).
+        pub fn try_tok_spanned(&mut self, skip_ws: bool) -> Result<Option<(TokenType, Range<usize>)>, LexError> {
+            let result = self.lex(skip_ws);
+            self.last_error = result.as_ref().err().cloned();
+            result
+        }
+
+        /// Returns the error of the most recent attempt to lex a token
+        ///
+        /// This is the error behind a `None` returned by [tok](Lexer::tok)
+        /// and its variants, including [peek](Lexer::peek). Returns None if the
+        /// most recent attempt produced a token or reached the end of input.
+        pub fn last_error(&self) -> Option<&LexError> {
+            self.last_error.as_ref()
+        }
+
+        /// Lexes the next Token, see [try_tok_spanned](Lexer::try_tok_spanned)
+        fn lex(&mut self, skip_ws: bool) -> Result<Option<(TokenType, Range<usize>)>, LexError> {
+            if let Some(ahead) = self.lookahead.pop_front() {
+                if ahead.skip_ws == skip_ws {
+                    self.load(ahead.after);
+                    return Ok(Some((ahead.token, ahead.span)));
+                }
+                self.lookahead.clear();
+            }
+
+            let before = self.state();
+            let token = self.lex_next(skip_ws)?;
+            if token.is_some() {
+                self.before_last = Some(before);
+            }
+            Ok(token)
+        }
+
+        /// Lexes the next Token, ignoring the tokens lexed ahead
+        fn lex_next(&mut self, skip_ws: bool) -> Result<Option<(TokenType, Range<usize>)>, LexError> {
+            if let Some(expansion) = self.expansion.take() {
+                let Expansion{ rule, span, next } = expansion;
+                let queued = self.dequeue((Some(rule), span.start), next, |lexer| {
+                    let ActionRef::Multi(action) = lexer.actions[rule].view() else { unreachable!() };
+                    action(&lexer.data[span.clone()])
+                });
+                if let Some((token, more)) = queued {
+                    if more {
+                        self.expansion = Some(Expansion{ rule, span: span.clone(), next: next + 1 });
+                    }
+                    self.last_span = Some(span.clone());
+                    self.last_rule = Some(rule);
+                    return Ok(Some((token, span)));
+                }
+            }
+
+            if let Some(line_tokens) = self.line_tokens.take() {
+                let hook = self.on_line_start.unwrap();
+                let queued = self.dequeue((None, self.curr_pos), line_tokens.next, |_| hook(line_tokens.indent, &mut line_tokens.indents.clone()));
+                if let Some((token, more)) = queued {
+                    if more {
+                        self.line_tokens = Some(LineTokens{ next: line_tokens.next + 1, ..line_tokens });
+                    }
+                    self.last_span = Some(self.curr_pos..self.curr_pos);
+                    self.last_rule = None;
+                    return Ok(Some((token, self.curr_pos..self.curr_pos)));
+                }
+            }
+
+            loop {
+                // Nothing is left to skip or match at the end of input
+                if skip_ws && self.curr_pos < self.end {
+                    if let Some(end) = match_at(&self.compiled.whitespace, &self.data, self.curr_pos, self.end) {
+                        self.advance_to(end);
+                    }
+                };
+
+                if self.streaming && self.curr_pos == self.end {
+                    return Err(self.error(LexErrorKind::Incomplete, self.curr_pos));
+                }
+
+                if let Some(hook) = self.on_line_start {
+                    let at_end = self.curr_pos == self.end;
+                    let pending = if at_end {
+                        self.line_started != usize::MAX
+                    } else {
+                        // Skipped input, e.g. a comment on a line of its own, does not start the line
+                        self.line_started < self.line
+                            && !self.candidates().first().is_some_and(|&(_, rule)| matches!(self.actions[rule].view(), ActionRef::Skip))
+                    };
+                    if pending {
+                        let indent = if at_end { 0 } else { self.column - 1 };
+                        self.line_started = if at_end { usize::MAX } else { self.line };
+                        let indents = self.indents.clone();
+                        let mut tokens = VecDeque::from(hook(indent, &mut self.indents));
+                        if let Some(token) = tokens.pop_front() {
+                            if !tokens.is_empty() {
+                                self.line_tokens = Some(LineTokens{ indent, indents, next: 1 });
+                                self.queued = Some(Queued{ source: (None, self.curr_pos), next: 1, tokens });
+                            }
+                            self.last_span = Some(self.curr_pos..self.curr_pos);
+                            self.last_rule = None;
+                            return Ok(Some((token, self.curr_pos..self.curr_pos)));
+                        }
+                    }
+                }
+
+                if self.curr_pos == self.end {
+                    return Ok(match self.eof_token {
+                        Some(make) if !self.eof_done => {
+                            self.eof_done = true;
+                            self.last_span = Some(self.curr_pos..self.curr_pos);
+                            self.last_rule = None;
+                            Some((make(), self.curr_pos..self.curr_pos))
+                        },
+                        _ => None,
+                    });
+                }
+
+                let candidates = self.candidates();
+                let longest = candidates.iter().map(|&(end, _)| end).max().unwrap_or(self.curr_pos);
+
+                // The token or a longer one might continue in the next chunk
+                if self.streaming && (candidates.is_empty() || longest == self.end) {
+                    return Err(self.error(LexErrorKind::Incomplete, self.curr_pos));
+                }
+
+                if let (Some(on_ambiguity), Strategy::Longest) = (self.on_ambiguity, self.match_strategy) {
+                    let priority = candidates.first().map(|&(_, rule)| self.priorities[rule]);
+                    let tied: Vec<_> = candidates.iter()
+                        .take_while(|&&(end, rule)| end == longest && Some(self.priorities[rule]) == priority)
+                        .map(|&(_, rule)| rule)
+                        .collect();
+                    if tied.len() > 1 {
+                        on_ambiguity(&tied, &self.data[self.curr_pos..longest]);
+                    }
+                }
+
+                let start = self.curr_pos;
+                let mut chosen = None;
+                for (end, rule) in candidates {
+                    // Rejected before the action runs, unless the action decides
+                    // whether and how much input its token consumes
+                    let decides = matches!(self.actions[rule].view(),
+                        ActionRef::Skip | ActionRef::Multi(_) | ActionRef::Fallible(_) | ActionRef::Contextual(_));
+                    if !decides && self.max_token_len.is_some_and(|max| end - start > max) {
+                        return Err(self.error(LexErrorKind::TooLong, start));
+                    }
+                    match self.run_action(rule, end) {
+                        Outcome::Decline => continue,
+                        Outcome::Failed(e) => {
+                            let snippet = self.data[start..end].to_string();
+                            return Err(LexError{ snippet, message: Some(e.to_string()), ..self.error(LexErrorKind::Action, start) });
+                        },
+                        Outcome::Resized(token, len) => {
+                            let end = start + len;
+                            assert!(len > 0 && self.data.is_char_boundary(end) && end <= self.end,
+                                "rule {} consumed {} bytes, which is not a non-empty part of the input", rule, len);
+                            chosen = Some((end, rule, Outcome::Token(token)));
+                            break;
+                        },
+                        outcome => { chosen = Some((end, rule, outcome)); break; },
+                    }
+                }
+
+                // The actions deciding on the token themselves are checked once
+                // they ran, skipped input is not limited
+                if let Some((end, rule, outcome)) = &chosen {
+                    let skipped = match outcome {
+                        Outcome::Skip => true,
+                        Outcome::Tokens(tokens) => tokens.is_empty(),
+                        _ => false,
+                    };
+                    if !skipped && self.max_token_len.is_some_and(|max| end - start > max) {
+                        return Err(self.error(LexErrorKind::TooLong, start));
+                    }
+                    self.change_mode(*rule);
+                }
+
+                match chosen {
+                    None => match self.error_token {
+                        Some(make) => {
+                            let end = start + self.remaining().chars().next().map_or(0, char::len_utf8);
+                            let token = make(&self.data[start..end]);
+                            self.advance_to(end);
+                            self.last_span = Some(start..end);
+                            self.last_rule = None;
+                            return Ok(Some((token, start..end)));
+                        },
+                        None => return Err(self.error(LexErrorKind::Unmatched, self.curr_pos)),
+                    },
+                    Some((end, rule, Outcome::Token(token))) => {
+                        self.advance_to(end);
+                        self.last_span = Some(start..end);
+                        self.last_rule = Some(rule);
+                        return Ok(Some((token, start..end)));
+                    },
+                    Some((end, rule, Outcome::Tokens(tokens))) => {
+                        self.advance_to(end);
+                        let mut tokens = VecDeque::from(tokens);
+                        let Some(token) = tokens.pop_front() else { continue };
+                        if !tokens.is_empty() {
+                            self.expansion = Some(Expansion{ rule, span: start..end, next: 1 });
+                            self.queued = Some(Queued{ source: (Some(rule), start), next: 1, tokens });
+                        }
+                        self.last_span = Some(start..end);
+                        self.last_rule = Some(rule);
+                        return Ok(Some((token, start..end)));
+                    },
+                    Some((end, _, _)) => self.advance_to(end),
+                }
+            }
+        }
+
+        /// Returns token `next` of the tokens produced at once by `source`, and whether more follow
+        ///
+        /// The tokens are queued when they are first produced. `produce` only
+        /// runs if the queue does not hold them, e.g. after restoring a
+        /// checkpoint in between them or in a clone of the Lexer.
+        fn dequeue(&mut self, source: (Option<usize>, usize), next: usize, produce: impl FnOnce(&Self) -> Vec<TokenType>) -> Option<(TokenType, bool)> {
+            if !self.queued.as_ref().is_some_and(|queued| queued.source == source && queued.next == next) {
+                let mut tokens = VecDeque::from(produce(self));
+                tokens.drain(..next.min(tokens.len()));
+                self.queued = Some(Queued{ source, next, tokens });
+            }
+            let queued = self.queued.as_mut().unwrap();
+            let token = queued.tokens.pop_front()?;
+            queued.next += 1;
+            Some((token, !queued.tokens.is_empty()))
+        }
+
+        /// Returns the end and index of all rules matching at the current position
+        ///
+        /// Sorted in the order they are tried by the match strategy, i.e. by
+        /// decreasing priority and then insertion order, for
+        /// [Longest](Strategy::Longest) first by decreasing length. Empty
+        /// matches are left out, as accepting them would never advance the Lexer.
+        fn candidates(&mut self) -> Vec<(usize, usize)> {
+            // The set only prunes the rules in a single pass over the input,
+            // just the rules that are known to match are run again to find
+            // the length of their match.
+            self.matched.clear();
+            self.compiled.rule_set.which_overlapping_matches(&anchored(&self.data, self.curr_pos, self.end), &mut self.matched);
+
+            let mut candidates = Vec::new();
+            for m in self.matched.iter().map(|m| m.as_usize()) {
+                if let Some(end) = self.rule_match(m) {
+                    candidates.push((end, m));
+                }
+            }
+
+            // Matches are yielded in insertion order, and the sort is stable
+            let priorities = &self.priorities;
+            match self.match_strategy {
+                Strategy::First => candidates.sort_by_key(|&(_, rule)| Reverse(priorities[rule])),
+                Strategy::Longest => candidates.sort_by_key(|&(end, rule)| (Reverse(end), Reverse(priorities[rule]))),
+            }
+            candidates
+        }
+
+        /// Returns the end of the match of `rule` at the current position if
+        /// it can produce a token there
+        ///
+        /// The rule has to be enabled and belong to the active mode, and its
+        /// match must not be empty and be followed by its lookahead.
+        fn rule_match(&self, rule: usize) -> Option<usize> {
+            if !self.enabled[rule] || self.modes[rule].as_ref() != self.mode_stack.last() {
+                return None;
+            }
+            let end = match_at(self.rule(rule), &self.data, self.curr_pos, self.end).filter(|&end| end > self.curr_pos)?;
+            match &self.compiled.follows[rule] {
+                Some(follow) => match_at(follow, &self.data, end, self.end).map(|_| end),
+                None => Some(end),
+            }
+        }
+
+        /// Returns the active lexical mode, None for the default mode
+        pub fn mode(&self) -> Option<&str> {
+            self.mode_stack.last().map(String::as_str)
+        }
+
+        /// Enters the lexical mode `mode`, see [push_in_mode](LexerBuilder::push_in_mode)
+        pub fn push_mode(&mut self, mode: &str) {
+            self.mode_stack.push(String::from(mode));
+            self.lookahead.clear();
+        }
+
+        /// Returns to the previous lexical mode and returns the mode that was left
+        ///
+        /// Returns None and stays in the default mode if no mode was entered.
+        pub fn pop_mode(&mut self) -> Option<String> {
+            self.lookahead.clear();
+            self.mode_stack.pop()
+        }
+
+        /// Applies the mode change of `rule` after it matched
+        fn change_mode(&mut self, rule: usize) {
+            match &self.transitions[rule] {
+                Some(Transition::Push(mode)) => self.mode_stack.push(mode.clone()),
+                Some(Transition::Pop) => { self.mode_stack.pop(); },
+                None => {},
+            }
+        }
+
+        /// Enables or disables rule `index` at runtime
+        ///
+        /// Disabled rules are ignored by [tok](Lexer::tok) and its variants
+        /// until enabled again, which allows simple lexical modes without
+        /// rebuilding the Lexer. All rules start out enabled. Rules are
+        /// numbered in insertion order.
+        ///
+        /// # Panics
+        ///
+        /// Panics if there is no rule with index `index`.
+        pub fn set_rule_enabled(&mut self, index: usize, enabled: bool) {
+            self.enabled[index] = enabled;
+            // Tokens lexed ahead might be lexed differently now
+            self.lookahead.clear();
+        }
+
+        /// Returns whether `rule` matches at the current position, without consuming input
+        ///
+        /// Like for [tok](Lexer::tok) the rule must be enabled and belong to
+        /// the active mode, its match must not be empty, and a lookahead must
+        /// match as well. Whitespace is not skipped and the action is not run.
+        /// Rules are numbered in insertion order.
+        ///
+        /// # Panics
+        ///
+        /// Panics if there is no rule with index `rule`.
+        pub fn matches_rule(&self, rule: usize) -> bool {
+            self.rule_match(rule).is_some()
+        }
+
+        /// Returns whether `pattern` matches at the current position, without consuming input
+        ///
+        /// Like for a rule the match must not be empty.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `pattern` is not a valid regex.
+        pub fn matches_pattern(&self, pattern: &str) -> bool {
+            let regex = meta::Regex::builder().syntax(self.compiled.syntax).build(pattern).unwrap();
+            match_at(&regex, &self.data, self.curr_pos, self.end).is_some_and(|end| end > self.curr_pos)
+        }
+
+        /// Returns the regex of `rule`, compiling it if necessary
+        fn rule(&self, rule: usize) -> &meta::Regex {
+            let compiled = &self.compiled;
+            compiled.rules[rule].get_or_init(|| {
+                // Searches are anchored, so reporting all matches yields the longest
+                let config = match compiled.longest[rule] {
+                    true => compiled.config.clone().match_kind(MatchKind::All),
+                    false => compiled.config.clone(),
+                };
+                meta::Regex::builder().configure(config).syntax(compiled.syntax).build(&compiled.patterns[rule]).unwrap()
+            })
+        }
+
+        /// Runs the action of `rule` on its match at the current position ending at `end`
+        fn run_action(&self, rule: usize, end: usize) -> Outcome<TokenType> {
+            let text = &self.data[self.curr_pos..end];
+            match self.actions[rule].view() {
+                ActionRef::Token(action) => Outcome::Token(action(text)),
+                ActionRef::Captures(action) => {
+                    let regex = self.compiled.captures[rule].as_ref().unwrap();
+                    Outcome::Token(action(&regex.captures_at(&self.data[..self.end], self.curr_pos).unwrap()))
+                },
+                ActionRef::Fallible(action) => match action(text) {
+                    Some(token) => Outcome::Token(token),
+                    None => Outcome::Decline,
+                },
+                ActionRef::Multi(action) => Outcome::Tokens(action(text)),
+                ActionRef::Try(action) => match action(text) {
+                    Ok(token) => Outcome::Token(token),
+                    Err(e) => Outcome::Failed(e),
+                },
+                ActionRef::Contextual(action) => match action(text, &self.data[end..self.end]) {
+                    (token, Some(len)) => Outcome::Resized(token, len),
+                    (token, None) => Outcome::Token(token),
+                },
+                ActionRef::Scratch(action) => {
+                    let mut scratch = self.scratch.lock().unwrap_or_else(PoisonError::into_inner);
+                    scratch.clear();
+                    Outcome::Token(action(text, &mut scratch))
+                },
+                ActionRef::Skip => Outcome::Skip,
+            }
+        }
+
+        /// Returns the number of tokens in the remaining input without consuming them
+        ///
+        /// Lexes a copy of the Lexer that borrows the input, so the actions are
+        /// run for each token. Returns an error at the first position no token
+        /// matches.
+        pub fn count_remaining(&self, skip_ws: bool) -> Result<usize, LexError> {
+            let mut copy = self.with_data(Cow::Borrowed(&self.data));
+            let mut count = 0;
+            while copy.try_tok(skip_ws)?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        /// Returns an iterator over the remaining tokens and their spans, skipping whitespace
+        ///
+        /// The iterator ends after the first error, so the tokens can be
+        /// collected into a `Result<Vec<_>, LexError>`.
+        pub fn spanned_tokens(&mut self) -> SpannedTokens<'_, 'a, TokenType, S> {
+            SpannedTokens{ lexer: self, failed: false }
+        }
+
+        /// Lexes all remaining input
+        ///
+        /// Returns an error at the first position no token matches.
+        pub fn tokenize_all(&mut self, skip_ws: bool) -> Result<Vec<TokenType>, LexError> {
+            let mut tokens = Vec::new();
+            while let Some(token) = self.try_tok(skip_ws)? {
+                tokens.push(token);
+            }
+            Ok(tokens)
+        }
+
+        /// Lexes all remaining input, skipping input no token matches
+        ///
+        /// Whenever no token matches, an error is recorded and the offending
+        /// char is skipped. Returns all tokens with their spans and all errors.
+        pub fn tokenize_recovering(&mut self, skip_ws: bool) -> (Vec<(TokenType, Range<usize>)>, Vec<LexError>) {
+            let mut tokens = Vec::new();
+            let mut errors = Vec::new();
+            loop {
+                match self.try_tok_spanned(skip_ws) {
+                    Ok(Some(token)) => tokens.push(token),
+                    Ok(None) => break,
+                    Err(e) if e.kind == LexErrorKind::Unmatched => {
+                        errors.push(e);
+                        let skipped = self.remaining().chars().next().map_or(0, char::len_utf8);
+                        self.advance_to(self.curr_pos + skipped);
+                    },
+                    Err(e) => { errors.push(e); break; },
+                }
+            }
+            (tokens, errors)
+        }
+
+        /// Returns the next Token if it satisfies `pred`
+        ///
+        /// Otherwise the Lexer is left at its previous position, and the error
+        /// contains the position and text of the rejected token.
+        pub fn expect<F: Fn(&TokenType) -> bool>(&mut self, skip_ws: bool, pred: F) -> Result<TokenType, LexError> {
+            let snapshot = self.checkpoint();
+            match self.try_tok_spanned(skip_ws)? {
+                Some((token, _)) if pred(&token) => Ok(token),
+                Some((_, span)) => {
+                    self.restore(snapshot);
+                    Err(LexError{ snippet: self.data[span.clone()].to_string(), ..self.error(LexErrorKind::Unexpected, span.start) })
+                },
+                None => {
+                    let pos = self.curr_pos;
+                    self.restore(snapshot);
+                    Err(self.error(LexErrorKind::UnexpectedEof, pos))
+                },
+            }
+        }
+
+        /// Returns the next Token without advancing the Lexer
+        ///
+        /// Same as [peek_n(0, skip_ws)](Lexer::peek_n), the token is buffered
+        /// and returned by the following call to [tok](Lexer::tok), so the
+        /// action of the matching rule is only run once.
+        pub fn peek(&mut self, skip_ws: bool) -> Option<&TokenType> {
+            self.peek_n(0, skip_ws)
+        }
+
+        /// Returns the rule and span of the next token without running its action
+        ///
+        /// Matches of [Skip](Action::Skip) rules are skipped like by
+        /// [tok](Lexer::tok). As no action is run, a
+        /// [Fallible](Action::Fallible) rule can not decline the match, and the
+        /// span is the match even if a [Contextual](Action::Contextual) rule
+        /// would consume a different length. Returns None at the end of input
+        /// or if no rule matches.
+        ///
+        /// Only matches of rules are reported. Tokens that belong to no rule,
+        /// i.e. those of the [on_line_start](LexerBuilder::on_line_start) hook
+        /// and the [eof_token](LexerBuilder::eof_token), are left out, even if
+        /// [tok](Lexer::tok) returns one of them first.
+        pub fn peek_raw(&mut self, skip_ws: bool) -> Option<(usize, Range<usize>)> {
+            if let Some(ahead) = self.lookahead.front().filter(|ahead| ahead.skip_ws == skip_ws) {
+                if let Some(rule) = ahead.after.last_rule {
+                    return Some((rule, ahead.span.clone()));
+                }
+            }
+            if let Some(expansion) = &self.expansion {
+                return Some((expansion.rule, expansion.span.clone()));
+            }
+
+            let snapshot = self.checkpoint();
+            let raw = loop {
+                if skip_ws {
+                    if let Some(end) = match_at(&self.compiled.whitespace, &self.data, self.curr_pos, self.end) {
+                        self.advance_to(end);
+                    }
+                }
+                if self.curr_pos == self.end {
+                    break None;
+                }
+                match self.candidates().first() {
+                    Some(&(end, rule)) if matches!(self.actions[rule].view(), ActionRef::Skip) => {
+                        self.advance_to(end);
+                        self.change_mode(rule);
+                    },
+                    Some(&(end, rule)) => break Some((rule, self.curr_pos..end)),
+                    None => break None,
+                }
+            };
+            self.load(snapshot);
+            raw
+        }
+
+        /// Returns the `n`th next Token without advancing the Lexer
+        ///
+        /// `peek_n(0, ..)` returns the next token. The tokens up to the `n`th
+        /// are lexed once and buffered, following calls to [tok](Lexer::tok)
+        /// with the same `skip_ws` return the buffered tokens. Returns None if
+        /// the input ends or can not be lexed before the `n`th token.
+        pub fn peek_n(&mut self, n: usize, skip_ws: bool) -> Option<&TokenType> {
+            if self.lookahead.front().is_some_and(|ahead| ahead.skip_ws != skip_ws) {
+                self.lookahead.clear();
+            }
+            if self.lookahead.len() <= n {
+                let current = self.checkpoint();
+                let mut lookahead = std::mem::take(&mut self.lookahead);
+                if let Some(last) = lookahead.back() {
+                    self.load(last.after.clone());
+                }
+                while lookahead.len() <= n {
+                    match self.try_tok_spanned(skip_ws) {
+                        Ok(Some((token, span))) => lookahead.push_back(Lookahead{ skip_ws, token, span, after: self.checkpoint() }),
+                        _ => break,
+                    }
+                }
+                self.load(current);
+                self.lookahead = lookahead;
+            }
+            self.lookahead.get(n).map(|ahead| &ahead.token)
+        }
+
+        /// Returns true if the end of input has been reached.
+        ///
+        /// Trailing whitespace, as configured by
+        /// [whitespace](LexerBuilder::whitespace), counts as end of input.
+        /// Tokens that are still to be returned, e.g. the further tokens of a
+        /// [Multi](Action::Multi) match or tokens lexed ahead by
+        /// [peek_n](Lexer::peek_n), do not. Neither does the pending call of
+        /// the [on_line_start](LexerBuilder::on_line_start) hook at the end of
+        /// input, even if it turns out to produce no tokens, or the
+        /// [eof_token](LexerBuilder::eof_token) that has not been returned yet.
+        pub fn is_eof(&self) -> bool {
+            if !self.lookahead.is_empty() || self.expansion.is_some() || self.line_tokens.is_some() {
+                return false;
+            }
+            if self.eof_token.is_some() && !self.eof_done {
+                return false;
+            }
+            if self.on_line_start.is_some() && self.line_started != usize::MAX {
+                return false;
+            }
+            self.curr_pos == self.end
+                || match_at(&self.compiled.whitespace, &self.data, self.curr_pos, self.end) == Some(self.end)
+        }
+    }
+
+    /// Iterates over the remaining tokens, skipping whitespace
+    ///
+    /// Iteration stops at the end of input or at the first position no token
+    /// matches.
+    impl<TokenType, S: Sharing> Iterator for Lexer<'_, TokenType, S> {
+        type Item = TokenType;
+
+        fn next(&mut self) -> Option<TokenType> {
+            self.tok(true)
+        }
+    }
+
+    /// Iterator over the remaining tokens and their spans, see [Lexer::spanned_tokens]
+    pub struct SpannedTokens<'l, 'a, TokenType, S: Sharing = Local> {
+        lexer: &'l mut Lexer<'a, TokenType, S>,
+        failed: bool,
+    }
+
+    impl<TokenType, S: Sharing> Iterator for SpannedTokens<'_, '_, TokenType, S> {
+        type Item = Result<(TokenType, Range<usize>), LexError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.failed {
+                return None;
+            }
+            let next = self.lexer.try_tok_spanned(true).transpose();
+            self.failed = matches!(next, Some(Err(_)));
+            next
+        }
+    }
+
+    /// The components of a [Lexer], see [into_parts](Lexer::into_parts)
+    ///
+    /// All `Vec`s are indexed by rule, in insertion order, and pattern `i` of
+    /// `rule_set` is rule `i`. The Lexer runs every regex anchored at
+    /// `position` against the whole of `data`, limiting the search to
+    /// `..end`, so that assertions see the text around the lexed range.
+    pub struct LexerParts<'a, TokenType, S: Sharing = Local> {
+        /// All rules at once, compiled with [MatchKind::All] to find every rule matching at a position
+        pub rule_set: meta::Regex,
+        /// Each rule on its own, to find the length of its match
+        pub rules: Vec<meta::Regex>,
+        /// Input that has to follow the match of a rule, see [push_with_lookahead](LexerBuilder::push_with_lookahead)
+        pub follows: Vec<Option<meta::Regex>>,
+        /// Rules with a [Captures](Action::Captures) action compiled with the regex crate
+        pub captures: Vec<Option<Regex>>,
+        /// The action of each rule
+        pub actions: Vec<S::Action<TokenType>>,
+        /// The [priority](LexAction::priority) of each rule
+        pub priorities: Vec<i32>,
+        /// Whether each rule is enabled, see [set_rule_enabled](Lexer::set_rule_enabled)
+        pub enabled: Vec<bool>,
+        /// The whitespace skipped between tokens
+        pub whitespace: meta::Regex,
+        /// The whole input
+        pub data: Cow<'a, str>,
+        /// Byte offset of the next token
+        pub position: usize,
+        /// End of the lexed range of `data`
+        pub end: usize,
+    }
+
+    /// Buffers the current token of a [Lexer] for a parser
+    ///
+    /// Wrap a configured Lexer with [new](TokenStream::new) after calling
+    /// [init](Lexer::init). Whitespace is skipped. The stream always holds
+    /// the next token, which a parser can inspect with
+    /// [current](TokenStream::current) and [span](TokenStream::span) before
+    /// consuming it with [advance](TokenStream::advance).
+    pub struct TokenStream<'a, TokenType, S: Sharing = Local> {
+        lexer: Lexer<'a, TokenType, S>,
+        current: Result<Option<(TokenType, Range<usize>)>, LexError>,
+    }
+
+    impl<'a, TokenType, S: Sharing> TokenStream<'a, TokenType, S> {
+        /// Wraps `lexer`, lexing its first token
+        pub fn new(mut lexer: Lexer<'a, TokenType, S>) -> Self {
+            let current = lexer.try_tok_spanned(true);
+            TokenStream{ lexer, current }
+        }
+
+        /// Returns the current token, or `None` at the end of input or after an error
+        pub fn current(&self) -> Option<&TokenType> {
+            match &self.current {
+                Ok(Some((token, _))) => Some(token),
+                _ => None,
+            }
+        }
+
+        /// Returns the span of the current token
+        ///
+        /// At the end of input, or after an error, this is the empty range at
+        /// the position of the Lexer.
+        pub fn span(&self) -> Range<usize> {
+            match &self.current {
+                Ok(Some((_, span))) => span.clone(),
+                _ => self.lexer.offset()..self.lexer.offset(),
+            }
+        }
+
+        /// Returns the error the Lexer stopped at instead of producing the current token
+        pub fn error(&self) -> Option<&LexError> {
+            self.current.as_ref().err()
+        }
+
+        /// Consumes the current token and lexes the next one
+        ///
+        /// Returns `None` without advancing at the end of input or after an error.
+        pub fn advance(&mut self) -> Option<TokenType> {
+            if !matches!(self.current, Ok(Some(_))) {
+                return None;
+            }
+            let next = self.lexer.try_tok_spanned(true);
+            std::mem::replace(&mut self.current, next).unwrap().map(|(token, _)| token)
+        }
+
+        /// Returns the Lexer, positioned after the current token
+        pub fn into_lexer(self) -> Lexer<'a, TokenType, S> {
+            self.lexer
+        }
+    }
+
+    /// What a [LexerBytes] does with the input matched by a rule
+    #[allow(clippy::type_complexity)]
+    pub enum BytesAction<TokenType> {
+        /// Converts the matched `&[u8]` to a `TokenType`
+        Token(Rc<dyn Fn(&[u8]) -> TokenType>),
+        /// Consumes the match without producing a token
+        Skip,
+    }
+
+    impl<TokenType> Clone for BytesAction<TokenType> {
+        fn clone(&self) -> Self {
+            match self {
+                BytesAction::Token(f) => BytesAction::Token(f.clone()),
+                BytesAction::Skip => BytesAction::Skip,
+            }
+        }
+    }
+
+    /// Struct used to generate a [LexerBytes]
+    ///
+    /// The counterpart of [LexerBuilder] for input that is not valid UTF-8.
+    /// Patterns are matched against bytes like with `regex::bytes`, so
+    /// `(?-u:\xFF)` matches the single byte `0xFF`.
+    pub struct LexerBytesBuilder<'s, TokenType> {
+        /// Patterns and actions of all rules
+        pub actions: Vec<(&'s str, BytesAction<TokenType>)>,
+        /// Regex representation of the whitespace skipped by `tok(true)`, `\s+` if unset
+        pub whitespace: Option<&'s str>,
+    }
+
+    impl<'s, TokenType> Default for LexerBytesBuilder<'s, TokenType> {
+        fn default() -> Self {
+            LexerBytesBuilder{ actions: Vec::new(), whitespace: None }
+        }
+    }
+
+    impl<'s, TokenType> LexerBytesBuilder<'s, TokenType> {
+        /// Creates a new LexerBytesBuilder
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Adds a new token, see [LexerBuilder::push]
+        pub fn push<F: Fn(&[u8]) -> TokenType + 'static>(&mut self, token: &'s str, action: F) -> &mut Self {
+            self.actions.push((token, BytesAction::Token(Rc::new(action))));
+            self
+        }
+
+        /// Adds a rule whose matches are consumed without producing a token
+        pub fn push_skip(&mut self, token: &'s str) -> &mut Self {
+            self.actions.push((token, BytesAction::Skip));
+            self
+        }
+
+        /// Sets the whitespace skipped before a token when `skip_ws` is true
+        pub fn whitespace(&mut self, token: &'s str) -> &mut Self {
+            self.whitespace = Some(token);
+            self
+        }
+
+        /// Builds a new LexerBytes
+        ///
+        /// # Panics
+        ///
+        /// Panics if any pattern is not a valid regex or if no rule has been added.
+        pub fn build<'a>(&self) -> LexerBytes<'a, TokenType> {
+            self.try_build().unwrap()
+        }
+
+        /// Builds a new LexerBytes, or returns an error naming the first invalid pattern
+        pub fn try_build<'a>(&self) -> Result<LexerBytes<'a, TokenType>, BuildError> {
+            if self.actions.is_empty() {
+                return Err(BuildError::no_rules());
+            }
+
+            let syntax = syntax::Config::new().utf8(false);
+            let config = meta::Config::new().utf8_empty(false);
+            let compile = |token: &str| {
+                meta::Regex::builder()
+                    .configure(config.clone())
+                    .syntax(syntax)
+                    .build(token)
+                    .map_err(|e| BuildError::from_meta(token, e))
+            };
+
+            let whitespace = compile(self.whitespace.unwrap_or(r"\s+"))?;
+            let rules = self.actions.iter().enumerate()
+                .map(|(rule, &(token, _))| compile(token).map_err(|e| BuildError{ rule: Some(rule), ..e }))
+                .collect::<Result<Vec<_>, _>>()?;
+            for (rule, &(token, _)) in self.actions.iter().enumerate() {
+                check_non_empty(rule, token, token, false, &syntax)?;
+            }
+            let patterns: Vec<_> = self.actions.iter().map(|&(token, _)| token).collect();
+            let rule_set = meta::Regex::builder()
+                .configure(config.match_kind(MatchKind::All))
+                .syntax(syntax)
+                .build_many(&patterns)
+                .map_err(|e| BuildError::from_meta(&patterns.join("|"), e))?;
+
+            Ok(LexerBytes{
+                whitespace,
+                matched: PatternSet::new(rule_set.pattern_len()),
+                rule_set,
+                rules,
+                actions: self.actions.iter().map(|(_, action)| action.clone()).collect(),
+                data: Cow::Borrowed(&[]),
+                curr_pos: 0,
+            })
+        }
+    }
+
+    /// Lexer for input that is not valid UTF-8, see [LexerBytesBuilder]
+    ///
+    /// Like [Lexer] it picks the longest match, ties are won by the rule
+    /// pushed first.
+    pub struct LexerBytes<'a, TokenType> {
+        whitespace: meta::Regex,
+        rule_set: meta::Regex,
+        rules: Vec<meta::Regex>,
+        matched: PatternSet,
+        actions: Vec<BytesAction<TokenType>>,
+        data: Cow<'a, [u8]>,
+        curr_pos: usize,
+    }
+
+    impl<'a, TokenType> LexerBytes<'a, TokenType> {
+        /// Resets the parser to the starting state with input data
+        pub fn init(&mut self, data: Vec<u8>) {
+            self.data = Cow::Owned(data);
+            self.curr_pos = 0;
+        }
+
+        /// Resets the parser to the starting state with borrowed input data
+        pub fn init_bytes(&mut self, data: &'a [u8]) {
+            self.data = Cow::Borrowed(data);
+            self.curr_pos = 0;
+        }
+
+        /// Returns the input that has not been lexed yet
+        pub fn remaining(&self) -> &[u8] {
+            &self.data[self.curr_pos..]
+        }
+
+        /// Returns the current byte offset into the input
+        pub fn offset(&self) -> usize {
+            self.curr_pos
+        }
+
+        /// Returns the next Token, or None if no token is found
+        pub fn tok(&mut self, skip_ws: bool) -> Option<TokenType> {
+            self.tok_spanned(skip_ws).map(|(token, _)| token)
+        }
+
+        /// Returns the next Token together with the byte range of input it was lexed from
+        pub fn tok_spanned(&mut self, skip_ws: bool) -> Option<(TokenType, Range<usize>)> {
+            self.try_tok_spanned(skip_ws).unwrap_or(None)
+        }
+
+        /// Returns the next Token
+        ///
+        /// Like [Lexer::try_tok], but the column of an error is counted in bytes,
+        /// and its snippet is decoded lossily.
+        pub fn try_tok(&mut self, skip_ws: bool) -> Result<Option<TokenType>, LexError> {
+            self.try_tok_spanned(skip_ws).map(|t| t.map(|(token, _)| token))
+        }
+
+        /// Returns the next Token together with the byte range of input it was lexed from
+        pub fn try_tok_spanned(&mut self, skip_ws: bool) -> Result<Option<(TokenType, Range<usize>)>, LexError> {
+            loop {
+                let data = &*self.data;
+                let anchored = |pos: usize| Input::new(data).range(pos..).anchored(Anchored::Yes);
+
+                if skip_ws {
+                    if let Some(m) = self.whitespace.search(&anchored(self.curr_pos)) {
+                        self.curr_pos = m.end();
+                    }
+                }
+                if self.curr_pos == data.len() {
+                    return Ok(None);
+                }
+
+                self.matched.clear();
+                self.rule_set.which_overlapping_matches(&anchored(self.curr_pos), &mut self.matched);
+                // Matches are yielded in insertion order, so the first longest one wins
+                let mut longest: Option<(usize, usize)> = None;
+                for rule in self.matched.iter().map(|m| m.as_usize()) {
+                    let end = self.rules[rule].search(&anchored(self.curr_pos)).unwrap().end();
+                    if end > longest.map_or(self.curr_pos, |(end, _)| end) {
+                        longest = Some((end, rule));
+                    }
+                }
+
+                let Some((end, rule)) = longest else {
+                    let before = &data[..self.curr_pos];
+                    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+                    let column = before.iter().rev().take_while(|&&b| b != b'\n').count() + 1;
+                    let snippet = String::from_utf8_lossy(&data[self.curr_pos..data.len().min(self.curr_pos + SNIPPET_LEN)]).into_owned();
+                    return Err(LexError{ kind: LexErrorKind::Unmatched, position: self.curr_pos, line, column, snippet, message: None });
+                };
+
+                let start = self.curr_pos;
+                self.curr_pos = end;
+                if let BytesAction::Token(action) = &self.actions[rule] {
+                    return Ok(Some((action(&self.data[start..end]), start..end)));
+                }
+            }
+        }
+    }
+
+    /// Iterates over the remaining tokens, skipping whitespace
+    impl<TokenType> Iterator for LexerBytes<'_, TokenType> {
+        type Item = TokenType;
+
+        fn next(&mut self) -> Option<TokenType> {
+            self.tok(true)
+        }
+    }
+}
+
+/// Defines a token enum together with the rules lexing it
+///
+/// Every variant is preceded by its pattern. Variants with a field get a
+/// default action parsing the matched text into the field with
+/// [str::parse], which panics if parsing fails. The enum gets an associated
+/// function `builder` returning a [LexerBuilder](lexer::LexerBuilder) with
+/// all rules pushed in order.
+///
+/// ```
+/// runtime_lexer::lexer! {
+///     #[derive(Debug, PartialEq)]
+///     enum Token {
+///         r"\d+"    => Int(i32),
+///         r"[a-z]+" => Word(String),
+///         r"\+"     => Plus,
+///     }
+/// }
+///
+/// let mut l = Token::builder().build();
+/// l.init(String::from("a + 1"));
+/// assert_eq!(l.tokenize_all(true), Ok(vec![Token::Word(String::from("a")), Token::Plus, Token::Int(1)]));
+/// ```
+#[macro_export]
+macro_rules! lexer {
+    (@action $name:ident $variant:ident ($ty:ty)) => {
+        |x: &str| $name::$variant(x.parse::<$ty>().expect(concat!("can not parse ", stringify!($variant))))
+    };
+    (@action $name:ident $variant:ident) => {
+        |_: &str| $name::$variant
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $( $pattern:literal => $variant:ident $( ($ty:ty) )? ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $( $variant $( ($ty) )? ),*
+        }
+
+        impl $name {
+            /// Returns a LexerBuilder with the rules of all variants
+            #[allow(dead_code)]
+            $vis fn builder() -> $crate::lexer::LexerBuilder<'static, $name> {
+                let mut builder = $crate::lexer::LexerBuilder::new();
+                $( builder.push($pattern, $crate::lexer!(@action $name $variant $( ($ty) )?)); )*
+                builder
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use core::panic;
+    use std::borrow::Cow;
+    use std::cell::{Cell, RefCell};
+    use std::error::Error;
+    use std::ops::Range;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use crate::lexer::{lex_one, Action, BuildError, BuildErrorKind, Checkpoint, Lexer, LexerBuilder, LexAction, LexError, LexErrorKind, LexerBytesBuilder, LexerParts, ModeChange, RuleSpec, SeekError, Shared, SpecError, Strategy, TokenStream, Warning};
+
+    #[test]
+    fn it_works() {
+        let result = 2 + 2;
+        assert_eq!(result, 4);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Token1 {
+        TokenInt    (i32),
+        TokenString (String),
+    }
+
+    #[test]
+    fn doesnt_panic_array(){
+        let _l: Lexer<Token1> = LexerBuilder{
+            actions: [LexAction{ token: r"\d+".into(), action: Action::Token(Rc::new(|x: &str| Token1::TokenInt( x.parse::<i32>().unwrap() ))), flags: None, follow: None, priority: 0, name: None, mode: None, mode_change: None, longest: false}].to_vec(),
+            ..Default::default()
+        }.build();
+    }
+
+    #[test]
+    fn doesnt_panic_append(){
+        let _l: Lexer<Token1> = LexerBuilder::new()
+            .push( r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push( r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+    }
+
+    #[test]
+    fn simple_number_test(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("42"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42);},
+            _ => { panic!("Token is not of type int"); },
+        }
+    }
+
+    #[test]
+    fn simple_number_leading_ws(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from(" 42"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+    }
+
+    #[test]
+    fn number_after_ws_run(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("   \n\n\n  42"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+    }
+
+    #[test]
+    fn identifier_after_mixed_ws(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("42 \t\r\n \t foo"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "foo", "Expected foo: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn two_numbers(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("42 52"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+ 
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 52);},
+            _ => { panic!("Token is not of type int"); },
+        }       
+    }
+
+    #[test]
+    fn many_numbers(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+        
+        l.init((0..100).map(|x: i8| x.to_string()).collect::<Vec<String>>().join(" "));
+
+        for i in 0..100 {
+            match l.tok(true).unwrap() {
+                Token1::TokenInt(v) => { assert!(v == i, "Expected {}: Actual {}", i, v);},
+                _ => { panic!("Token is not of type int"); },
+            }
+        }
+    }
+
+    #[test]
+    fn test_eof(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("42"));
+
+        assert!(!l.is_eof());
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn non_ascii_identifiers(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"\w+", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("café 42 λx 7 naïve"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "café", "Expected café: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "λx", "Expected λx: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 7, "Expected 7: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "naïve", "Expected naïve: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn emoji_between_numbers(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",       |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[^\s\d]+", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("1🎉2 é🦀λ 3"));
+
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 1, "Expected 1: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "🎉", "Expected 🎉: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 2, "Expected 2: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenString(v) => { assert!(v == "é🦀λ", "Expected é🦀λ: Actual: {}", v);},
+            _ => { panic!("Token is not of type string"); },
+        }
+        match l.tok(true).unwrap() {
+            Token1::TokenInt(v) => { assert!(v == 3, "Expected 3: Actual: {}", v);},
+            _ => { panic!("Token is not of type int"); },
+        }
+
+        assert!(l.is_eof());
+        assert!(l.tok(true).is_none());
+    }
+
+    #[test]
+    fn collect_tokens(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 2 3"));
+
+        let tokens: Vec<_> = l.collect();
+        assert_eq!(tokens, vec![Token1::TokenInt(1), Token1::TokenInt(2), Token1::TokenInt(3)]);
+    }
+
+    #[test]
+    fn iterate_by_ref(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 2 3 "));
+
+        let mut count = 0;
+        for t in &mut l {
+            count += 1;
+            assert_eq!(t, Token1::TokenInt(count));
+        }
+        assert_eq!(count, 3);
+        assert!(l.next().is_none());
+    }
+
+    #[test]
+    fn peek_does_not_advance(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from(" 42 52"));
+
+        assert_eq!(l.peek(true), Some(&Token1::TokenInt(42)));
+        assert_eq!(l.peek(true), Some(&Token1::TokenInt(42)));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+        assert_eq!(l.peek(true), Some(&Token1::TokenInt(52)));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(52)));
+        assert_eq!(l.peek(true), None);
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn try_tok_outcomes(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("42 $foo"));
+
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(42))));
+        assert_eq!(l.try_tok(true), Err(LexError{ kind: LexErrorKind::Unmatched, position: 3, line: 1, column: 4, snippet: String::from("$foo"), message: None }));
+
+        l.init(String::from("42  "));
+
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(42))));
+        assert_eq!(l.try_tok(true), Ok(None));
+    }
+
+    #[test]
+    fn line_and_column(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",  |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[=\w]+", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("a = 1\nbb = 2\n\n  λ = 42"));
+
+        assert_eq!(l.position(), (1, 1));
+
+        for _ in 0..4 {
+            l.tok(true);
+        }
+        assert_eq!(l.position(), (2, 3));
+
+        assert_eq!(l.peek(true), Some(&Token1::TokenString(String::from("="))));
+        assert_eq!(l.position(), (2, 3));
+
+        for _ in 0..4 {
+            l.tok(true);
+        }
+        assert_eq!(l.position(), (4, 6));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+        assert_eq!(l.position(), (4, 9));
+    }
+
+    #[test]
+    fn tokenize_all(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 2 3 "));
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token1::TokenInt(1), Token1::TokenInt(2), Token1::TokenInt(3)]));
+        assert!(l.is_eof());
+
+        l.init(String::from("1 2 x 3"));
+        assert_eq!(l.tokenize_all(true), Err(LexError{ kind: LexErrorKind::Unmatched, position: 4, line: 1, column: 5, snippet: String::from("x 3"), message: None }));
+    }
+
+    #[test]
+    fn skip_line_comments(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push_skip(r"//[^\n]*")
+            .build();
+
+        l.init(String::from("1 // first\n// second 3\n2 // last"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+        assert_eq!(l.tok(true), None);
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn custom_whitespace(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"\n",  |x: &str| Token1::TokenString(String::from(x)))
+            .whitespace(r"[ \t]+")
+            .build();
+
+        l.init(String::from("1 \t2\n\t3"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token1::TokenInt(1),
+            Token1::TokenInt(2),
+            Token1::TokenString(String::from("\n")),
+            Token1::TokenInt(3),
+        ]));
+    }
+
+    #[test]
+    fn stateful_closure(){
+        let counter = Rc::new(Cell::new(0));
+        let c = counter.clone();
+
+        let mut l = LexerBuilder::<(usize, i32)>::new()
+            .push(r"\d+", move |x: &str| {
+                c.set(c.get() + 1);
+                (c.get(), x.parse::<i32>().unwrap())
+            })
+            .build();
+
+        l.init(String::from("10 20 30"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![(1, 10), (2, 20), (3, 30)]));
+        assert_eq!(counter.get(), 3);
+    }
+
+    fn parse_int(x: &str) -> Token1 {
+        Token1::TokenInt(x.parse::<i32>().unwrap())
+    }
+
+    #[test]
+    fn fn_pointer_action(){
+        let action: fn(&str) -> Token1 = parse_int;
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", action)
+            .build();
+
+        l.init(String::from("7"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(7)));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Time {
+        hour:   u32,
+        minute: u32,
+    }
+
+    #[test]
+    fn capture_groups(){
+        let mut l = LexerBuilder::<Time>::new()
+            .push_captures(r"(\d+):(?P<minute>\d+)", |c| Time{
+                hour:   c[1].parse().unwrap(),
+                minute: c["minute"].parse().unwrap(),
+            })
+            .build();
+
+        l.init(String::from("12:30 7:05"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![Time{ hour: 12, minute: 30 }, Time{ hour: 7, minute: 5 }]));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Token2 {
+        If,
+        Select,
+        Keyword(String),
+        Ident(String),
+    }
+
+    #[test]
+    fn tie_identifier_first(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .push(r"if",     |_: &str| Token2::If)
+            .build();
+
+        l.init(String::from("if iffy"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token2::Ident(String::from("if")), Token2::Ident(String::from("iffy"))]));
+    }
+
+    #[test]
+    fn tie_keyword_first(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"if",     |_: &str| Token2::If)
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .build();
+
+        l.init(String::from("if iffy"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token2::If, Token2::Ident(String::from("iffy"))]));
+    }
+
+    #[test]
+    fn try_build_invalid_pattern(){
+        let res = LexerBuilder::<Token1>::new()
+            .push(r"\d+",       |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[unclosed", |x: &str| Token1::TokenString(String::from(x)))
+            .try_build();
+
+        match res {
+            Err(BuildError{ pattern, .. }) => { assert_eq!(pattern, "[unclosed"); },
+            Ok(_) => { panic!("Invalid pattern was accepted"); },
+        }
+    }
+
+    #[test]
+    fn large_input(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init((0..50_000).map(|x: i32| format!("{} x{}", x, x)).collect::<Vec<String>>().join("\n"));
+
+        let tokens = l.tokenize_all(true).unwrap();
+        assert_eq!(tokens.len(), 100_000);
+        assert_eq!(tokens[99_998], Token1::TokenInt(49_999));
+    }
+
+    #[test]
+    fn borrowed_input(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        let data = "x 1 y 2";
+        l.init_str(&data[2..]);
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token1::TokenInt(1),
+            Token1::TokenString(String::from("y")),
+            Token1::TokenInt(2),
+        ]));
+    }
+
+    #[test]
+    fn seek_back(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",  |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"\w+", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("1\nλ 2"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        let pos = l.offset();
+        assert_eq!(pos, 1);
+
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("λ"))));
+        assert_eq!(l.position(), (2, 2));
+        assert_eq!(l.offset(), 4);
+
+        assert_eq!(l.seek(pos), Ok(()));
+        assert_eq!(l.position(), (1, 2));
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("λ"))));
+
+        assert_eq!(l.seek(3), Err(SeekError{ position: 3 }));
+        assert_eq!(l.seek(100), Err(SeekError{ position: 100 }));
+        assert_eq!(l.offset(), 4);
+
+        assert_eq!(l.seek(6), Ok(()));
+        assert!(l.is_eof());
+    }
+
+    #[test]
+    fn empty_matches_terminate(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d*", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap_or(-1)))
+            .push_skip(r"a?")
+            .build();
+
+        l.init(String::from("12 x 3"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(12)));
+        assert_eq!(l.try_tok(true), Err(LexError{ kind: LexErrorKind::Unmatched, position: 3, line: 1, column: 4, snippet: String::from("x 3"), message: None }));
+        assert_eq!(l.offset(), 3);
+
+        l.init(String::from("12 a 3"));
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token1::TokenInt(12), Token1::TokenInt(3)]));
+    }
+
+    #[test]
+    fn case_insensitive(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"select", |_: &str| Token2::Select)
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .case_insensitive(true)
+            .build();
+
+        l.init(String::from("SELECT Select sElEcTs"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token2::Select, Token2::Select, Token2::Ident(String::from("sElEcTs"))]));
+    }
+
+    #[test]
+    fn per_rule_flags(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_with_flags(r"select", "i", |_: &str| Token2::Select)
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .build();
+
+        l.init(String::from("SELECT Select foo FOO"));
+
+        assert_eq!(l.tok(true), Some(Token2::Select));
+        assert_eq!(l.tok(true), Some(Token2::Select));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("foo"))));
+        assert_eq!(l.try_tok(true), Err(LexError{ kind: LexErrorKind::Unmatched, position: 18, line: 1, column: 19, snippet: String::from("FOO"), message: None }));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Token3 {
+        Int(i32),
+        Whole(i32),
+        Dot,
+    }
+
+    #[test]
+    fn lookahead(){
+        let mut l = LexerBuilder::<Token3>::new()
+            .push_with_lookahead(r"\d+", r"\.\d", |x: &str| Token3::Whole(x.parse::<i32>().unwrap()))
+            .push(r"\d+", |x: &str| Token3::Int(x.parse::<i32>().unwrap()))
+            .push(r"\.",  |_: &str| Token3::Dot)
+            .build();
+
+        l.init(String::from("3.14"));
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token3::Whole(3), Token3::Dot, Token3::Int(14)]));
+
+        l.init(String::from("3."));
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token3::Int(3), Token3::Dot]));
+    }
+
+    #[test]
+    fn spans(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("42 hello"));
+
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenInt(42), 0..2)));
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenString(String::from("hello")), 3..8)));
+        assert_eq!(l.tok_spanned(true), None);
+    }
+
+    #[test]
+    fn word_boundary_sees_preceding_text(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"\bif\b", |_: &str| Token2::If)
+            .push(r"[a-z]",   |x: &str| Token2::Ident(String::from(x)))
+            .build();
+
+        l.init(String::from("xif if"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token2::Ident(String::from("x")),
+            Token2::Ident(String::from("i")),
+            Token2::Ident(String::from("f")),
+            Token2::If,
+        ]));
+    }
+
+    #[test]
+    fn reset(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("a 1\nb 2"));
+
+        let first = l.tokenize_all(true).unwrap();
+        assert!(l.is_eof());
+
+        l.reset();
+        assert_eq!(l.position(), (1, 1));
+        assert_eq!(l.input(), "a 1\nb 2");
+        assert_eq!(l.tokenize_all(true).unwrap(), first);
+    }
+
+    #[test]
+    fn backtrack(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("1  foo 2"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        let (token, span) = l.tok_spanned(true).unwrap();
+        assert_eq!(token, Token1::TokenString(String::from("foo")));
+
+        l.backtrack(span.start);
+        assert_eq!(l.offset(), 3);
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo"))));
+
+        assert!(l.unput());
+        assert!(!l.unput());
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo"))));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+    }
+
+    #[test]
+    fn eof_token(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .eof_token(|| Token1::TokenString(String::from("EOF")))
+            .build();
+
+        l.init(String::from("1 "));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert!(!l.is_eof());
+        assert_eq!(l.peek(true), Some(&Token1::TokenString(String::from("EOF"))));
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("EOF"))));
+        assert!(l.is_eof());
+        assert_eq!(l.tok(true), None);
+
+        l.reset();
+        let tokens: Vec<_> = l.collect();
+        assert_eq!(tokens, vec![Token1::TokenInt(1), Token1::TokenString(String::from("EOF"))]);
+    }
+
+    #[test]
+    fn eof_trailing_ws(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("42   "));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+        assert!(l.is_eof());
+        assert_eq!(l.try_tok(true), Ok(None));
+    }
+
+    type Rule1 = (&'static str, fn(&str) -> Token1);
+
+    const RULES: [Rule1; 3] = [
+        (r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap())),
+        (r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x))),
+        (r"[+*/-]",       |x: &str| Token1::TokenString(String::from(x))),
+    ];
+
+    #[test]
+    fn extend_from_array(){
+        let mut l = LexerBuilder::new()
+            .extend(RULES)
+            .build();
+
+        l.init(String::from("a + 2"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token1::TokenString(String::from("a")),
+            Token1::TokenString(String::from("+")),
+            Token1::TokenInt(2),
+        ]));
+    }
+
+    #[test]
+    fn collect_builder(){
+        let mut l = RULES.into_iter().collect::<LexerBuilder<_>>().build();
+
+        l.init(String::from("3*b"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token1::TokenInt(3),
+            Token1::TokenString(String::from("*")),
+            Token1::TokenString(String::from("b")),
+        ]));
+    }
+
+    #[test]
+    fn owned_pattern(){
+        let keywords = ["if", "else", "while"];
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_owned(format!(r"\b(?:{})\b", keywords.join("|")), |x: &str| Token2::Keyword(String::from(x)))
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .build();
+
+        l.init(String::from("while whiles"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![Token2::Keyword(String::from("while")), Token2::Ident(String::from("whiles"))]));
+    }
+
+    #[test]
+    fn clone_mid_stream(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 2 3"));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+
+        let mut speculative = l.clone();
+        assert_eq!(speculative.tok(true), Some(Token1::TokenInt(2)));
+        assert_eq!(speculative.tok(true), Some(Token1::TokenInt(3)));
+        assert!(speculative.is_eof());
+
+        assert_eq!(l.offset(), 1);
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+    }
+
+    #[test]
+    fn expect(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        let is_int = |t: &Token1| matches!(t, Token1::TokenInt(_));
+
+        l.init(String::from("1 foo"));
+
+        assert_eq!(l.expect(true, is_int), Ok(Token1::TokenInt(1)));
+        assert_eq!(l.expect(true, is_int), Err(LexError{ kind: LexErrorKind::Unexpected, position: 2, line: 1, column: 3, snippet: String::from("foo"), message: None }));
+        assert_eq!(l.offset(), 1);
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo"))));
+        assert_eq!(l.expect(true, is_int).unwrap_err().kind, LexErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn remaining_and_consumed(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 22 333"));
+
+        assert_eq!(l.remaining(), "1 22 333");
+        assert_eq!(l.consumed(), "");
+        l.tok(true);
+        assert_eq!(l.remaining(), " 22 333");
+        assert_eq!(l.consumed(), "1");
+        l.tok(true);
+        assert_eq!(l.remaining(), " 333");
+        l.tok(true);
+        assert_eq!(l.remaining(), "");
+        assert_eq!(l.consumed(), "1 22 333");
+    }
+
+    #[test]
+    fn feed_chunks(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.feed("12");
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Incomplete);
+        assert_eq!(l.offset(), 0);
+
+        l.feed("3 4");
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(123))));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Incomplete);
+
+        l.feed("5 ");
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(45))));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Incomplete);
+
+        l.feed("6");
+        l.finish();
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(6))));
+        assert_eq!(l.try_tok(true), Ok(None));
+
+        // A range up to the end of the data grows
+        l.init_range(String::from("ab 1"), 3..4);
+        l.feed("2");
+        assert_eq!(l.range(), 3..5);
+        l.finish();
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(12))));
+    }
+
+    #[test]
+    #[should_panic(expected = "can not feed input to the range 3..4 of input with length 8")]
+    fn feed_bounded_range(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+        l.init_range(String::from("ab 1 cde"), 3..4);
+        l.feed("2");
+    }
+
+    thread_local! {
+        static AMBIGUITIES: RefCell<Vec<(Vec<usize>, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[test]
+    fn ambiguity_hook(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"\d+",    |x: &str| Token2::Ident(String::from(x)))
+            .push(r"abc",    |_: &str| Token2::If)
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .on_ambiguity(|rules, text| AMBIGUITIES.with(|a| a.borrow_mut().push((rules.to_vec(), String::from(text)))))
+            .build();
+
+        l.init(String::from("abcd abc 1"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token2::Ident(String::from("abcd")),
+            Token2::If,
+            Token2::Ident(String::from("1")),
+        ]));
+        AMBIGUITIES.with(|a| assert_eq!(*a.borrow(), vec![(vec![1, 2], String::from("abc"))]));
+    }
+
+    crate::lexer! {
+        #[derive(Debug, PartialEq)]
+        enum Token4 {
+            r"\d+"         => Int(i32),
+            r"\d+\.\d+"    => Float(f64),
+            r"[a-zA-Z]\w*" => Word(String),
+            r"="           => Assign,
+        }
+    }
+
+    #[test]
+    fn lexer_macro(){
+        let mut l = Token4::builder().build();
+
+        l.init(String::from("x = 1.5 y=2"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token4::Word(String::from("x")),
+            Token4::Assign,
+            Token4::Float(1.5),
+            Token4::Word(String::from("y")),
+            Token4::Assign,
+            Token4::Int(2),
+        ]));
+    }
+
+    #[test]
+    fn fallible_action(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push_fallible(r"\d+", |x: &str| x.parse::<i32>().ok().map(Token1::TokenInt))
+            .push(r"\d+", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("42 99999999999"));
+
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token1::TokenInt(42),
+            Token1::TokenString(String::from("99999999999")),
+        ]));
+    }
+
+    #[test]
+    fn tokenize_recovering(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+
+        l.init(String::from("1 $ 2 λ3"));
+
+        let (tokens, errors) = l.tokenize_recovering(true);
+        assert_eq!(tokens, vec![(Token1::TokenInt(1), 0..1), (Token1::TokenInt(2), 4..5), (Token1::TokenInt(3), 8..9)]);
+        assert_eq!(errors.iter().map(|e| e.position).collect::<Vec<_>>(), vec![2, 6]);
+        assert!(errors.iter().all(|e| e.kind == LexErrorKind::Unmatched));
+    }
+
+    #[test]
+    fn match_at_start_of_input(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d*",  |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-z]", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        l.init(String::from("x"));
+        assert_eq!(l.tok_spanned(false), Some((Token1::TokenString(String::from("x")), 0..1)));
+
+        l.init(String::from("7"));
+        assert_eq!(l.tok_spanned(false), Some((Token1::TokenInt(7), 0..1)));
+
+        l.init(String::from("?"));
+        assert_eq!(l.try_tok(false), Err(LexError{ kind: LexErrorKind::Unmatched, position: 0, line: 1, column: 1, snippet: String::from("?"), message: None }));
+    }
+
+    #[test]
+    fn debug_output(){
+        let mut builder = LexerBuilder::<Token1>::new();
+        builder
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)));
+
+        let debug = format!("{:?}", builder);
+        assert!(debug.contains(r#""\\d+""#), "{}", debug);
+        assert!(debug.contains(r#""[a-zA-Z_]\\w*""#), "{}", debug);
+
+        let mut l = builder.build();
+        l.init(String::from("12 foo"));
+        l.tok(true);
+
+        let debug = format!("{:?}", l);
+        assert!(debug.contains("offset: 2"), "{}", debug);
+        assert!(debug.contains("len: 6"), "{}", debug);
+        assert!(debug.contains(r#"remaining: " foo""#), "{}", debug);
+    }
+
+    #[test]
+    fn last_match(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("  foo_bar 42"));
+
+        assert_eq!(l.last_match(), None);
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo_bar"))));
+        assert_eq!(l.last_match(), Some("foo_bar"));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+        assert_eq!(l.last_match(), Some("42"));
+    }
+
+    #[test]
+    fn anchored_alternation(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"a|bc",  |x: &str| Token2::Keyword(String::from(x)))
+            .push(r"^x|yz", |x: &str| Token2::Ident(String::from(x)))
+            .build();
+
+        l.init(String::from("a bc yz"));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::from("a"))));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::from("bc"))));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("yz"))));
+
+        l.init(String::from("bc"));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::from("bc"))));
+
+        // Neither branch may match later in the input
+        l.init(String::from("qa"));
+        assert_eq!(l.try_tok(true), Err(LexError{ kind: LexErrorKind::Unmatched, position: 0, line: 1, column: 1, snippet: String::from("qa"), message: None }));
+    }
+
+    #[test]
+    fn lex_error_display(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("12\n  x $y"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(12)));
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("x"))));
+
+        let err = l.try_tok(true).unwrap_err();
+        assert_eq!(err, LexError{ kind: LexErrorKind::Unmatched, position: 7, line: 2, column: 5, snippet: String::from("$y"), message: None });
+        assert_eq!(err.to_string(), "no token matches at 2:5 (byte 7): `$y`");
+
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert!(boxed.to_string().contains("$y"));
+    }
+
+    #[test]
+    fn init_range(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+
+        let doc = "say \"12 foo\nbar3\" now";
+        l.init_str_range(doc, 5..15);
+
+        assert_eq!(l.position(), (1, 6));
+        assert_eq!(l.remaining(), "12 foo\nbar");
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenInt(12), 5..7)));
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo"))));
+        // The identifier would continue with "3", but the range ends before it
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenString(String::from("bar")), 12..15)));
+        assert_eq!(l.position(), (2, 4));
+        assert_eq!(l.remaining(), "");
+        assert!(l.is_eof());
+        assert_eq!(l.tok(true), None);
+
+        assert_eq!(l.seek(3), Err(SeekError{ position: 3 }));
+        assert_eq!(l.seek(16), Err(SeekError{ position: 16 }));
+        l.reset();
+        assert_eq!(l.tokenize_all(true).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn peek_n(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("1 foo\n2"));
+
+        assert_eq!(l.peek_n(1, true), Some(&Token1::TokenString(String::from("foo"))));
+        assert_eq!(l.peek_n(0, true), Some(&Token1::TokenInt(1)));
+        assert_eq!(l.peek_n(3, true), None);
+        assert_eq!(l.offset(), 0);
+
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenInt(1), 0..1)));
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenString(String::from("foo")), 2..5)));
+        assert_eq!(l.position(), (1, 6));
+
+        // Returning a buffered token restores its span
+        assert!(l.unput());
+        assert_eq!(l.offset(), 2);
+        assert_eq!(l.tok(true), Some(Token1::TokenString(String::from("foo"))));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+        assert_eq!(l.tok(true), None);
+
+        // peek keeps the buffer, a clone lexes the buffered tokens again
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", move |x: &str| {
+                counted.set(counted.get() + 1);
+                Token1::TokenInt(x.parse::<i32>().unwrap())
+            })
+            .build();
+        l.init(String::from("1 2 3"));
+        assert_eq!(l.peek_n(1, true), Some(&Token1::TokenInt(2)));
+        assert_eq!(l.peek(true), Some(&Token1::TokenInt(1)));
+        let mut copy = l.clone();
+        assert_eq!(copy.tokenize_all(true).unwrap(), vec![Token1::TokenInt(1), Token1::TokenInt(2), Token1::TokenInt(3)]);
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+        assert_eq!(calls.get(), 5);
+    }
+
+    #[test]
+    fn next_token(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .default_skip_ws(true)
+            .build();
+        l.init(String::from(" 1  foo 2 "));
+
+        assert_eq!(l.next_token(), Some(Token1::TokenInt(1)));
+        assert_eq!(l.next_token(), Some(Token1::TokenString(String::from("foo"))));
+        assert_eq!(l.next_token(), Some(Token1::TokenInt(2)));
+        assert_eq!(l.next_token(), None);
+
+        // Overriding the default
+        l.reset();
+        assert_eq!(l.tok(false), None);
+    }
+
+    #[test]
+    fn push_multi(){
+        #[derive(Debug, PartialEq)]
+        enum Op { Less, Shift, Int(i32) }
+
+        let mut l = LexerBuilder::<Op>::new()
+            .push(r"<",     |_: &str| Op::Less)
+            .push_multi(r"<<", |_: &str| vec![Op::Less, Op::Less])
+            .push(r">>",    |_: &str| Op::Shift)
+            .push_multi(r"0+", |x: &str| x.chars().skip(1).map(|_| Op::Int(0)).collect())
+            .push(r"\d+",   |x: &str| Op::Int(x.parse::<i32>().unwrap()))
+            .build();
+        l.init(String::from("1 << 2 0 >> 000"));
+
+        assert_eq!(l.tok(true), Some(Op::Int(1)));
+        assert_eq!(l.tok_spanned(true), Some((Op::Less, 2..4)));
+        assert_eq!(l.peek(true), Some(&Op::Less));
+        assert_eq!(l.tok_spanned(false), Some((Op::Less, 2..4)));
+        assert_eq!(l.tok(true), Some(Op::Int(2)));
+        // "0" expands to no tokens at all
+        assert_eq!(l.tok(true), Some(Op::Shift));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Op::Int(0), Op::Int(0)]);
+
+        // The action runs once per match, and queued tokens are not the end of input
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut l = LexerBuilder::<Op>::new()
+            .push_multi(r"<+", move |x: &str| {
+                counted.set(counted.get() + 1);
+                x.chars().map(|_| Op::Less).collect()
+            })
+            .build();
+        l.init(String::from("<<<"));
+        let mut tokens = Vec::new();
+        while !l.is_eof() {
+            tokens.push(l.tok(true).unwrap());
+        }
+        assert_eq!(tokens, vec![Op::Less, Op::Less, Op::Less]);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn count_remaining(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("1 2 3"));
+
+        assert_eq!(l.count_remaining(true), Ok(3));
+        assert_eq!(l.offset(), 0);
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert_eq!(l.count_remaining(true), Ok(2));
+        assert_eq!(l.position(), (1, 2));
+
+        l.init(String::from("1 $"));
+        assert_eq!(l.count_remaining(true).unwrap_err().kind, LexErrorKind::Unmatched);
+    }
+
+    #[test]
+    fn from_specs(){
+        let spec = |pattern: &str, tag: &str| RuleSpec{ pattern: String::from(pattern), tag: String::from(tag) };
+        let specs = vec![spec(r"\d+", "int"), spec(r"[a-zA-Z_]\w*", "ident")];
+        let actions: [Rule1; 2] = [
+            ("int",   |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap())),
+            ("ident", |x: &str| Token1::TokenString(String::from(x))),
+        ];
+
+        let mut l = LexerBuilder::from_specs(&specs, &actions).unwrap().build();
+        l.init(String::from("foo 42"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token1::TokenString(String::from("foo")), Token1::TokenInt(42)]);
+
+        let specs = vec![spec(r"\d+", "float")];
+        assert_eq!(LexerBuilder::from_specs(&specs, &actions).err(), Some(SpecError{ tag: String::from("float") }));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_specs_json(){
+        let specs = vec![
+            RuleSpec{ pattern: String::from(r"\d+"), tag: String::from("int") },
+            RuleSpec{ pattern: String::from(r"[a-zA-Z_]\w*"), tag: String::from("ident") },
+        ];
+        let json = serde_json::to_string(&specs).unwrap();
+        assert_eq!(json, r#"[{"pattern":"\\d+","tag":"int"},{"pattern":"[a-zA-Z_]\\w*","tag":"ident"}]"#);
+
+        let specs: Vec<RuleSpec> = serde_json::from_str(&json).unwrap();
+        let actions: [Rule1; 2] = [
+            ("int",   |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap())),
+            ("ident", |x: &str| Token1::TokenString(String::from(x))),
+        ];
+        let mut l = LexerBuilder::from_specs(&specs, &actions).unwrap().build();
+        l.init(String::from("foo 42"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token1::TokenString(String::from("foo")), Token1::TokenInt(42)]);
+    }
+
+    #[test]
+    fn dot_matches_newline_and_multi_line(){
+        let build = |dotall: bool, multi_line: bool| LexerBuilder::<Token2>::new()
+            .push(r"/\*.*?\*/",   |x: &str| Token2::Keyword(String::from(x)))
+            .push(r"^[a-z]+",     |x: &str| Token2::Ident(String::from(x)))
+            .dot_matches_newline(dotall)
+            .multi_line(multi_line)
+            .build();
+
+        let mut l = build(true, false);
+        l.init(String::from("/* a\nb */"));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::from("/* a\nb */"))));
+
+        let mut l = build(false, false);
+        l.init(String::from("/* a\nb */"));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+
+        let mut l = build(false, true);
+        l.init(String::from("foo\nbar"));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("foo"))));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("bar"))));
+
+        let mut l = build(false, false);
+        l.init(String::from("foo\nbar"));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("foo"))));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+    }
+
+    #[test]
+    fn try_build_empty(){
+        let err = LexerBuilder::<Token1>::new().try_build().err().unwrap();
+        assert_eq!(err.pattern, "");
+        assert_eq!(err.kind, BuildErrorKind::NoRules);
+        assert!(err.source().is_none());
+        assert_eq!(err.to_string(), "invalid lexer: no rules have been added");
+
+        let err = LexerBytesBuilder::<Token1>::new().try_build().err().unwrap();
+        assert_eq!(err.kind, BuildErrorKind::NoRules);
+    }
+
+    #[test]
+    fn tok_with_rule(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .eof_token(|| Token1::TokenInt(-1))
+            .build();
+        l.init(String::from("foo 4"));
+
+        assert_eq!(l.tok_with_rule(true), Some((Token1::TokenString(String::from("foo")), 1)));
+        assert_eq!(l.tok_with_rule(true), Some((Token1::TokenInt(4), 0)));
+        assert_eq!(l.tok_with_rule(true), Some((Token1::TokenInt(-1), 2)));
+        assert_eq!(l.tok_with_rule(true), None);
+    }
+
+    #[test]
+    fn significant_newlines(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"[a-z]+", |x: &str| Token2::Ident(String::from(x)))
+            .push(r"\n",     |x: &str| Token2::Keyword(String::from(x)))
+            .significant_newlines(true)
+            .build();
+        l.init(String::from("a \t\n b"));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Ident(String::from("a")),
+            Token2::Keyword(String::from("\n")),
+            Token2::Ident(String::from("b")),
+        ]);
+    }
+
+    #[test]
+    fn skip_until(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .push_skip(r";")
+            .build();
+        l.init(String::from("1; $$ 2; 3"));
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert_eq!(l.try_tok(true).unwrap_err().position, 3);
+        assert_eq!(l.skip_until(";"), 5);
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(3)));
+
+        l.reset();
+        assert_eq!(l.skip_until(r"\$"), 4);
+        assert_eq!(l.skip_until("x"), 6);
+        assert_eq!(l.tok(true), None);
+    }
+
+    #[test]
+    fn push_contextual(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_contextual(r"[a-z]+", |x: &str, rest: &str| match rest.starts_with('(') {
+                true => (Token2::Keyword(String::from(x)), None),
+                false => (Token2::Ident(String::from(x)), None),
+            })
+            .push_contextual(r"#", |_: &str, rest: &str| {
+                let comment = rest.split('\n').next().unwrap();
+                (Token2::Keyword(String::from(comment)), Some(1 + comment.len()))
+            })
+            .push(r"[()]", |x: &str| Token2::Ident(String::from(x)))
+            .build();
+        l.init(String::from("f(x) #a (b)\ny"));
+
+        assert_eq!(l.tok_spanned(true), Some((Token2::Keyword(String::from("f")), 0..1)));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("("))));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("x"))));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from(")"))));
+        assert_eq!(l.tok_spanned(true), Some((Token2::Keyword(String::from("a (b)")), 5..11)));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("y"))));
+        assert_eq!(l.tok(true), None);
+    }
+
+    #[test]
+    fn push_str(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"if",       |_: &str| Token2::If)
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+        l.init(String::from("if iffy"));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token2::If, Token2::Ident(String::from("iffy"))]);
+    }
+
+    #[test]
+    fn non_clone_tokens(){
+        // Neither Clone nor Debug, to make sure no API silently requires them
+        #[derive(PartialEq)]
+        struct Word(String);
+
+        let mut l = LexerBuilder::<Word>::new()
+            .push_str(r"\w+", Word)
+            .build();
+        l.init(String::from("a b c"));
+
+        assert!(l.peek(true) == Some(&Word(String::from("a"))));
+        assert!(l.peek_n(1, true) == Some(&Word(String::from("b"))));
+        assert!(l.clone().tok(true) == Some(Word(String::from("a"))));
+        assert!(l.tok(true) == Some(Word(String::from("a"))));
+        assert!(l.tok(true) == Some(Word(String::from("b"))));
+        assert!(l.tok(true) == Some(Word(String::from("c"))));
+        assert!(l.tok(true).is_none());
+    }
+
+    #[test]
+    fn max_token_len(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .max_token_len(4)
+            .build();
+        l.init(String::from("abcd abcde"));
+
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("abcd"))));
+        assert_eq!(l.try_tok(true), Err(LexError{ kind: LexErrorKind::TooLong, position: 5, line: 1, column: 6, snippet: String::from("abcde"), message: None }));
+        assert_eq!(l.offset(), 5);
+
+        // The action of a too long match is not run
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut l = LexerBuilder::<Token2>::new()
+            .push(r"[a-z]+", move |x: &str| {
+                counted.set(counted.get() + 1);
+                Token2::Ident(String::from(x))
+            })
+            .max_token_len(4)
+            .build();
+        l.init(String::from("abcde"));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::TooLong);
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn max_token_len_first(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"a", Token2::Ident)
+            .push_str(r"a+", Token2::Keyword)
+            .match_strategy(Strategy::First)
+            .max_token_len(3)
+            .build();
+        l.init(String::from("aaaaa"));
+
+        // The longer match of `a+` is never chosen, so it is not too long
+        assert_eq!(l.tokenize_all(true), Ok((0..5).map(|_| Token2::Ident(String::from("a"))).collect()));
+    }
+
+    #[test]
+    fn max_token_len_skip_decline_resized(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_rest_of_line(r"rem", |x: &str| Token2::Keyword(String::from(x)))
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push_fallible(r"[a-z]+\d+", |_: &str| None)
+            .push_skip(r"#[^\n]*")
+            .max_token_len(4)
+            .build();
+
+        // A long comment is skipped, a declined long match gives way to a short one
+        l.init(String::from("# a long comment\nab123"));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("ab"))));
+
+        // The token of push_rest_of_line is longer than its match
+        l.init(String::from("rem a long line"));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::TooLong);
+        l.init(String::from("rem"));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::new())));
+    }
+
+    #[test]
+    fn match_strategy(){
+        let build = |strategy: Strategy| LexerBuilder::<Token2>::new()
+            .push(r"if",   |_: &str| Token2::If)
+            .push_str(r"[a-z]+", Token2::Ident)
+            .match_strategy(strategy)
+            .build();
+
+        let mut l = build(Strategy::Longest);
+        l.init(String::from("iffy if"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token2::Ident(String::from("iffy")), Token2::If]);
+
+        let mut l = build(Strategy::First);
+        l.init(String::from("iffy if"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token2::If, Token2::Ident(String::from("fy")), Token2::If]);
+    }
+
+    #[test]
+    fn patterns(){
+        let l = LexerBuilder::<Token2>::new()
+            .push(r"if", |_: &str| Token2::If)
+            .push_with_flags(r"select", "i", |_: &str| Token2::Select)
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+
+        assert_eq!(l.patterns(), vec!["if", "select", "[a-z]+"]);
+    }
+
+    #[test]
+    fn line_col(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"\w+", Token2::Ident)
+            .build();
+        l.init(String::from("ab\nλc\n\nd"));
+
+        assert_eq!(l.line_col(0), (1, 1));
+        assert_eq!(l.line_col(2), (1, 3));
+        assert_eq!(l.line_col(3), (2, 1));
+        assert_eq!(l.line_col(5), (2, 2));
+        // Inside of λ
+        assert_eq!(l.line_col(4), (2, 1));
+        assert_eq!(l.line_col(7), (3, 1));
+        assert_eq!(l.line_col(9), (4, 2));
+        assert_eq!(l.line_col(100), (4, 2));
+    }
+
+    #[test]
+    fn rebuild(){
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder.push(r"if", |_: &str| Token2::If);
+
+        let mut first = builder.build();
+        let mut second = builder.build();
+        assert!(Arc::ptr_eq(&first.compiled, &second.compiled));
+        first.init(String::from("if"));
+        second.init(String::from("if"));
+        assert_eq!(first.tok(true), Some(Token2::If));
+        assert_eq!(second.tok(true), Some(Token2::If));
+
+        // Changes to the builder are picked up by the next build
+        builder.case_insensitive(true);
+        let mut l = builder.build();
+        assert!(!Arc::ptr_eq(&first.compiled, &l.compiled));
+        l.init(String::from("IF"));
+        assert_eq!(l.tok(true), Some(Token2::If));
+
+        // Building another Lexer in between does not evict the cached regexes
+        let other = LexerBuilder::<Token2>::new().push(r"else", |_: &str| Token2::If).build();
+        assert!(!Arc::ptr_eq(&other.compiled, &l.compiled));
+        assert!(Arc::ptr_eq(&builder.build().compiled, &l.compiled));
+
+        builder.push_str(r"[a-z]+", Token2::Ident);
+        let mut l = builder.build();
+        l.init(String::from("iffy"));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("iffy"))));
+    }
+
+    #[test]
+    fn peek_raw(){
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", move |x: &str| { counter.set(counter.get() + 1); Token1::TokenInt(x.parse::<i32>().unwrap()) })
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .push_skip(r"#[^\n]*")
+            .build();
+        l.init(String::from("#c\n 42 foo"));
+
+        assert_eq!(l.peek_raw(true), Some((0, 4..6)));
+        assert_eq!(l.offset(), 0);
+        assert_eq!(calls.get(), 0);
+
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+        assert_eq!(l.peek_raw(false), None);
+        assert_eq!(l.peek_raw(true), Some((1, 7..10)));
+        assert_eq!(l.position(), (2, 4));
+        assert_eq!(calls.get(), 1);
+
+        // Tokens of no rule are left out
+        let word = |x: &str| Token1::TokenString(String::from(x));
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .on_line_start(|_, _| vec![Token1::TokenString(String::from("line"))])
+            .eof_token(|| Token1::TokenString(String::from("EOF")))
+            .build();
+        l.init(String::from("7"));
+        assert_eq!(l.peek(true), Some(&word("line")));
+        assert_eq!(l.peek_raw(true), Some((0, 0..1)));
+        assert_eq!(l.tok(true), Some(word("line")));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(7)));
+        assert_eq!(l.peek_n(1, true), Some(&word("EOF")));
+        assert_eq!(l.peek_raw(true), None);
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![word("line"), word("EOF")]);
+    }
+
+    #[test]
+    fn comment_tokens(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"#.*",    Token2::Keyword)
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+        l.init(String::from("# doc for a\na # trailing\n  b\n#last"));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Keyword(String::from("# doc for a")),
+            Token2::Ident(String::from("a")),
+            Token2::Keyword(String::from("# trailing")),
+            Token2::Ident(String::from("b")),
+            Token2::Keyword(String::from("#last")),
+        ]);
+    }
+
+    #[test]
+    fn from_actions(){
+        let ident = |x: &str| Token2::Ident(String::from(x));
+        let builder = LexerBuilder::from_actions([
+            LexAction::new(r"if", Action::Token(Rc::new(|_: &str| Token2::If))),
+            LexAction::new(r"[a-z]+", Action::Token(Rc::new(ident))),
+            LexAction::new(r"if", Action::Token(Rc::new(|_: &str| Token2::Select))),
+            LexAction{ flags: Some("i"), ..LexAction::new(r"if", Action::Token(Rc::new(|_: &str| Token2::Select))) },
+        ]);
+
+        assert_eq!(builder.actions.iter().map(|a| (&*a.token, a.flags)).collect::<Vec<_>>(), vec![
+            ("if", None),
+            ("[a-z]+", None),
+            ("if", Some("i")),
+        ]);
+        // The dropped rule keeps its index in the list, the later rule moved down
+        assert_eq!(builder.validate(), vec![Warning{ rule: 2, pattern: String::from("if"), shadowed_by: 0 }]);
+
+        let mut l = builder.build();
+        l.init(String::from("if IF"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token2::If, Token2::Select]);
+
+        // A duplicate with a higher priority wins the tie, so it is kept
+        let builder = LexerBuilder::from_actions([
+            LexAction::new(r"if", Action::Token(Rc::new(|_: &str| Token2::If))),
+            LexAction{ priority: 1, ..LexAction::new(r"if", Action::Token(Rc::new(|_: &str| Token2::Select))) },
+        ]);
+        assert_eq!(builder.rule_count(), 2);
+        let mut l = builder.build();
+        l.init(String::from("if"));
+        assert_eq!(l.tok(true), Some(Token2::Select));
+    }
+
+    #[test]
+    fn matches_rule(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("42"));
+
+        assert!(l.matches_rule(0));
+        assert!(!l.matches_rule(1));
+        assert!(l.matches_pattern(r"4"));
+        assert!(!l.matches_pattern(r"2"));
+        assert!(!l.matches_pattern(r"x*"));
+        assert_eq!(l.offset(), 0);
+
+        // Rules tok would not try do not match either
+        l.set_rule_enabled(0, false);
+        assert!(!l.matches_rule(0));
+        l.set_rule_enabled(0, true);
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(42)));
+
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push_in_mode("upper", r"[a-z]+", |x: &str| Token2::Keyword(String::from(x)))
+            .build();
+        l.init(String::from("abc"));
+        assert!(l.matches_rule(0));
+        assert!(!l.matches_rule(1));
+        l.push_mode("upper");
+        assert!(!l.matches_rule(0));
+        assert!(l.matches_rule(1));
+    }
+
+    #[test]
+    fn error_token(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .error_token(|x: &str| Token2::Keyword(String::from(x)))
+            .build();
+        l.init(String::from("ab$λ cd!"));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Ident(String::from("ab")),
+            Token2::Keyword(String::from("$")),
+            Token2::Keyword(String::from("λ")),
+            Token2::Ident(String::from("cd")),
+            Token2::Keyword(String::from("!")),
+        ]);
+    }
+
+    #[test]
+    fn progress(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .build();
+        l.init(String::from("12 45"));
+
+        assert_eq!(l.len(), 5);
+        assert_eq!(l.progress(), 0.0);
+        l.tok(true);
+        assert!((l.progress() - 0.4).abs() < 1e-9);
+        l.tok(true);
+        assert_eq!(l.progress(), 1.0);
+
+        l.init(String::new());
+        assert!(l.is_empty());
+        assert_eq!(l.progress(), 1.0);
+    }
+
+    #[test]
+    fn lex_bytes(){
+        #[derive(Debug, PartialEq)]
+        enum Frame { Header, Len(u8), Text(String) }
+
+        let mut l = LexerBytesBuilder::<Frame>::new()
+            .push(r"(?-u:\xFF\xFE)",  |_: &[u8]| Frame::Header)
+            .push(r"(?-u:\x01[\x00-\xFF])", |x: &[u8]| Frame::Len(x[1]))
+            .push(r"[a-z]+",          |x: &[u8]| Frame::Text(String::from_utf8(x.to_vec()).unwrap()))
+            .build();
+        l.init_bytes(b"\xFF\xFE\x01\x80 abc\xFF");
+
+        assert_eq!(l.tok(true), Some(Frame::Header));
+        assert_eq!(l.tok(true), Some(Frame::Len(0x80)));
+        assert_eq!(l.tok_spanned(true), Some((Frame::Text(String::from("abc")), 5..8)));
+        let err = l.try_tok(true).unwrap_err();
+        assert_eq!((err.kind, err.position, err.column), (LexErrorKind::Unmatched, 8, 9));
+        assert_eq!(l.remaining(), b"\xFF");
+    }
+
+    #[test]
+    fn add_rule(){
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder.push_str(r"[A-Z]+", Token2::Ident);
+        let mut l = builder.build();
+        l.init(String::from("X select X"));
+
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("X"))));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+        l.add_rule(r"select", |_: &str| Token2::Select).unwrap();
+        assert_eq!(l.tok(true), Some(Token2::Select));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("X"))));
+        assert_eq!(l.patterns(), vec!["[A-Z]+", "select"]);
+
+        assert_eq!(l.add_rule(r"(", |_: &str| Token2::If).unwrap_err().rule, Some(2));
+        // Rejected like by try_build
+        let error = l.add_rule(r"|abc", |_: &str| Token2::If).unwrap_err();
+        assert_eq!(error, BuildError{ pattern: String::from("|abc"), rule: Some(2), kind: BuildErrorKind::EmptyMatch });
+        assert_eq!(l.patterns().len(), 2);
+
+        // Lexers built from the same builder are not affected
+        let mut other = builder.build();
+        other.init(String::from("select"));
+        assert_eq!(other.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+    }
+
+    #[test]
+    fn end_anchor_mid_input(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+$", Token2::Keyword)
+            .push_str(r"[a-z]+",  Token2::Ident)
+            .build();
+
+        l.init(String::from("ab cd\nef"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Ident(String::from("ab")),
+            Token2::Ident(String::from("cd")),
+            Token2::Keyword(String::from("ef")),
+        ]);
+
+        // The end of a range is not the end of input
+        l.init_range(String::from("ab cd"), 0..2);
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("ab"))));
+    }
+
+    #[test]
+    fn push_with_preprocess(){
+        fn unquote(x: &str) -> Cow<'_, str> {
+            Cow::Borrowed(&x[1..x.len() - 1])
+        }
+        fn lowercase(x: &str) -> Cow<'_, str> {
+            Cow::Owned(x.to_lowercase())
+        }
+
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_with_preprocess(r#""[^"]*""#, unquote, |x: &str| Token2::Keyword(String::from(x)))
+            .push_with_preprocess(r"[a-zA-Z]+", lowercase, |x: &str| Token2::Ident(String::from(x)))
+            .build();
+        l.init(String::from(r#""a b" FoO"#));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Keyword(String::from("a b")),
+            Token2::Ident(String::from("foo")),
+        ]);
+    }
 
-/// Contains the main lexer
-pub mod lexer {
-    use regex::{Regex, RegexSet};
-    use lazy_static::lazy_static;
+    #[test]
+    fn spanned_tokens(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
 
-    /// Represents a Lexer Action mapping a regex representation to a TokenType
-    #[derive(Clone)]
-    pub struct LexAction<'s, TokenType> {
-        /// Regex representation of a token
-        pub token:  &'s str,
-        /// Function converting a `&str` token to a `TokenType`
-        pub action: fn(&str) -> TokenType,
+        l.init(String::from("1 ab"));
+        assert_eq!(l.spanned_tokens().collect::<Result<Vec<_>, _>>(), Ok(vec![
+            (Token1::TokenInt(1), 0..1),
+            (Token1::TokenString(String::from("ab")), 2..4),
+        ]));
+
+        l.init(String::from("1 $ ab"));
+        assert_eq!(l.spanned_tokens().collect::<Result<Vec<_>, _>>().unwrap_err().position, 2);
+
+        l.init(String::from("1 $ ab"));
+        assert_eq!(l.spanned_tokens().count(), 2);
     }
 
-    /// Struct used to generate a Lexer
-    ///
-    /// It can either be initialised with an array of LexActions, or using the
-    /// [push](LexerBuilder::push) method(recommended).
-    #[derive(Default)]
-    pub struct LexerBuilder<'s, TokenType> {
-        /// List of all tokens including conversions used by the resulting Lexer
-        pub actions: Vec<LexAction<'s, TokenType>>,
+    #[test]
+    fn validate(){
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push(r"if",         |_: &str| Token2::If)
+            .push(r"IF",         |_: &str| Token2::If)
+            .push_str(r"[a-z]+", Token2::Keyword)
+            .push(r"select\b",   |_: &str| Token2::Select);
+
+        assert_eq!(builder.validate(), vec![
+            Warning{ rule: 1, pattern: String::from("if"), shadowed_by: 0 },
+            Warning{ rule: 3, pattern: String::from("[a-z]+"), shadowed_by: 0 },
+        ]);
+        assert_eq!(builder.validate()[0].to_string(), "rule 1 `if` is shadowed by rule 0");
+
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder
+            .push(r"if",         |_: &str| Token2::If)
+            .push_str(r"[a-z]+", Token2::Ident);
+        assert!(builder.validate().is_empty());
     }
 
-    /// Represents a finished Lexer
-    pub struct Lexer<TokenType> {
-        regex_set: RegexSet,
-        regexes: Vec<Regex>,
-        actions: Vec<fn(&str) -> TokenType>,
-        data: String,
-        curr_pos: usize,
+    #[test]
+    fn push_try(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push_try(r"\d+", |x: &str| Ok(Token1::TokenInt(x.parse::<i32>()?)))
+            .build();
+        l.init(String::from("1\n 99999999999 2"));
+
+        assert_eq!(l.try_tok(true), Ok(Some(Token1::TokenInt(1))));
+        let err = l.try_tok(true).unwrap_err();
+        assert_eq!((err.kind, err.position, err.line, err.column), (LexErrorKind::Action, 3, 2, 2));
+        assert_eq!(err.snippet, "99999999999");
+        assert_eq!(err.to_string(), "invalid token at 2:2 (byte 3): `99999999999`: number too large to fit in target type");
+        assert_eq!(l.offset(), 3);
     }
 
-    impl<'s, TokenType> LexerBuilder<'s, TokenType> {
-        /// Returns an empty LexerBuilder
-        pub fn new() -> Self{
-            LexerBuilder{ actions: Vec::new() }
-        }
+    #[test]
+    fn checkpoint(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        l.init(String::from("0\n1 foo 2 3"));
+        l.tok(true);
 
-        /// Adds a new token to the LexerBuilder
-        ///
-        /// token is the regex representation of the string  
-        /// action is a method converting the &str representation of the token to a Token
-        pub fn push(&mut self, token: &'s str, action: fn(&str) -> TokenType) -> &mut Self {
-            self.actions.push(LexAction{ token, action });
-            self
-        }
+        let checkpoint: Checkpoint = l.checkpoint();
+        let first: Vec<_> = (0..3).map(|_| l.tok_spanned(true)).collect();
+        assert_eq!(l.position(), (2, 8));
 
-        /// Builds a new Lexer from the Actions configured in the Builder
-        pub fn build(&self) -> Lexer<TokenType>{
-            Lexer{
-                regex_set: RegexSet::new(self.actions.iter().map(|a| String::from("^") + &a.token )).unwrap(),
-                regexes: self.actions.iter().map(|a| Regex::new(&(String::from("^") + &a.token)).unwrap()).collect(),
-                actions: self.actions.iter().map(|a| a.action ).collect(),
-                data: String::new(),
-                curr_pos: 0,
+        l.restore(checkpoint);
+        assert_eq!(l.position(), (1, 2));
+        let second: Vec<_> = (0..3).map(|_| l.tok_spanned(true)).collect();
+        assert_eq!(first, second);
+        assert_eq!(second[2], Some((Token1::TokenInt(2), 8..9)));
+    }
+
+    #[test]
+    fn scratch_buffer(){
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let seen = calls.clone();
+        let mut l = LexerBuilder::<Token1>::new()
+            .push_scratch(r#""(\\.|[^"\\])*""#, move |x: &str, buf: &mut String| {
+                seen.borrow_mut().push((buf.is_empty(), buf.capacity()));
+                buf.reserve(64);
+                let mut chars = x[1..x.len() - 1].chars();
+                while let Some(c) = chars.next() {
+                    buf.push(if c == '\\' { chars.next().unwrap() } else { c });
+                }
+                Token1::TokenInt(buf.len() as i32)
+            })
+            .build();
+        l.init(String::from(r#""a\"b" "" "cd" "\\""#));
+        let tokens: Vec<_> = std::iter::from_fn(|| l.tok(true)).collect();
+        assert_eq!(tokens, vec![Token1::TokenInt(3), Token1::TokenInt(0), Token1::TokenInt(2), Token1::TokenInt(1)]);
+
+        let calls = calls.borrow();
+        assert_eq!(calls.len(), 4);
+        assert!(calls.iter().all(|&(empty, _)| empty));
+        assert_eq!(calls[0].1, 0);
+        // The capacity reserved in the first call is kept
+        assert!(calls[1].1 >= 64);
+        assert!(calls[1..].iter().all(|&(_, capacity)| capacity == calls[1].1));
+    }
+
+    #[test]
+    fn priority(){
+        for keyword_first in [false, true] {
+            let mut builder = LexerBuilder::<Token2>::new();
+            if keyword_first {
+                builder.push_with_priority(r"if", 1, |_: &str| Token2::If);
             }
+            builder.push_str(r"[a-z]+", Token2::Ident);
+            if !keyword_first {
+                builder.push_with_priority(r"if", 1, |_: &str| Token2::If);
+            }
+            builder.on_ambiguity(|_, text| panic!("{} is ambiguous", text));
+            let mut l = builder.build();
+
+            l.init(String::from("if iff"));
+            assert_eq!(l.tok(true), Some(Token2::If));
+            assert_eq!(l.tok(true), Some(Token2::Ident(String::from("iff"))));
+            assert!(builder.validate().is_empty());
         }
+
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder.push_with_priority(r"[a-z]+", 1, |x: &str| Token2::Ident(String::from(x)))
+            .push(r"if", |_: &str| Token2::If);
+        assert_eq!(builder.validate(), vec![Warning{ rule: 1, pattern: String::from("if"), shadowed_by: 0 }]);
     }
 
-    impl<TokenType> Lexer<TokenType> {
-        /// Resets the parser to the starting state with input data
-        pub fn init(&mut self, data: String){
-            self.data = data;
-            self.curr_pos = 0;
+    #[test]
+    fn tok_past_eof(){
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+", move |x: &str| {
+                counted.set(counted.get() + 1);
+                Token1::TokenInt(x.parse::<i32>().unwrap())
+            })
+            .build();
+        l.init(String::from("1 2 "));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![Token1::TokenInt(1), Token1::TokenInt(2)]);
+        assert_eq!(calls.get(), 2);
+
+        let state = (l.offset(), l.position(), l.last_match().map(String::from));
+        for _ in 0..3 {
+            assert_eq!(l.tok(true), None);
+            assert_eq!(l.tok(false), None);
+            assert_eq!((l.offset(), l.position(), l.last_match().map(String::from)), state);
         }
+        assert_eq!(calls.get(), 2);
+        assert_eq!(l.remaining(), "");
+    }
 
-        /// Returns the next Token, or None if no token is found
-        pub fn tok(&mut self, skip_ws: bool) -> Option<TokenType> {
-            println!("{}", &self.data[self.curr_pos..]);
-            if skip_ws {
-                lazy_static! {
-                    static ref WS: Regex = Regex::new(r"^\s").unwrap();
-                }
+    #[test]
+    fn token_stream(){
+        #[derive(Debug, PartialEq)]
+        enum Tok {
+            Num(i32),
+            Plus,
+            Times,
+            Open,
+            Close,
+        }
 
-                let res = WS.find(&self.data[self.curr_pos..]);
-                match res {
-                    Some(v) => { self.curr_pos = v.end() + self.curr_pos; }
-                    None => ()
-                };
-            };
-            println!("{} {}\n", self.curr_pos, &self.data[self.curr_pos..]);
+        use std::ops::Range;
 
-            let matches: Vec<_> = self.regex_set.matches(&self.data[self.curr_pos..]).into_iter().collect();
+        // sum := product ('+' product)*, product := atom ('*' atom)*, atom := num | '(' sum ')'
+        fn sum(s: &mut TokenStream<Tok>) -> Result<i32, Range<usize>> {
+            let mut value = product(s)?;
+            while s.current() == Some(&Tok::Plus) {
+                s.advance();
+                value += product(s)?;
+            }
+            Ok(value)
+        }
+        fn product(s: &mut TokenStream<Tok>) -> Result<i32, Range<usize>> {
+            let mut value = atom(s)?;
+            while s.current() == Some(&Tok::Times) {
+                s.advance();
+                value *= atom(s)?;
+            }
+            Ok(value)
+        }
+        fn atom(s: &mut TokenStream<Tok>) -> Result<i32, Range<usize>> {
+            match s.current() {
+                Some(&Tok::Num(n)) => {
+                    s.advance();
+                    Ok(n)
+                },
+                Some(Tok::Open) => {
+                    s.advance();
+                    let value = sum(s)?;
+                    match s.advance() {
+                        Some(Tok::Close) => Ok(value),
+                        _ => Err(s.span()),
+                    }
+                },
+                _ => Err(s.span()),
+            }
+        }
 
-            if matches.is_empty() {
-                return None;
+        let mut builder = LexerBuilder::<Tok>::new();
+        builder.push(r"\d+", |x: &str| Tok::Num(x.parse().unwrap()))
+            .push(r"\+",  |_: &str| Tok::Plus)
+            .push(r"\*",  |_: &str| Tok::Times)
+            .push(r"\(",  |_: &str| Tok::Open)
+            .push(r"\)",  |_: &str| Tok::Close);
+        let parse = |input: &str| {
+            let mut l = builder.build();
+            l.init(String::from(input));
+            let mut s = TokenStream::new(l);
+            let value = sum(&mut s)?;
+            match s.current() {
+                None if s.error().is_none() => Ok(value),
+                _ => Err(s.span()),
             }
+        };
 
-            let mut longest = 0;
-            let mut longest_id = 0;
+        assert_eq!(parse("2 * (3 + 4) + 1"), Ok(15));
+        assert_eq!(parse("7"), Ok(7));
+        assert_eq!(parse("2 + * 3"), Err(4..5));
+        assert_eq!(parse("(1 + 2"), Err(6..6));
+        assert_eq!(parse("1 + 2 x"), Err(6..6));
 
-            for m in matches {
-                println!("{}", self.curr_pos);
-                let length = self.regexes[m].find(&self.data[self.curr_pos..]).unwrap().end() + self.curr_pos;
-                if length > longest {
-                    longest = length;
-                    longest_id = m;
-                }
-            };
+        let mut l = builder.build();
+        l.init(String::from("1 2"));
+        let mut s = TokenStream::new(l);
+        assert_eq!(s.span(), 0..1);
+        assert_eq!(s.advance(), Some(Tok::Num(1)));
+        let mut l = s.into_lexer();
+        assert_eq!(l.tok(true), None);
+    }
 
-            let token = self.actions[longest_id](&self.data[self.curr_pos..longest]);
-            self.curr_pos = longest;
-            Some(token)
-        }
+    #[test]
+    fn trivia(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push_skip(r"#[^\n]*")
+            .build();
+        let input = "  foo \t bar # comment\n\nbaz\n# end\n";
+        l.init(String::from(input));
 
-        /// Returns true if the end of input has been reached.
-        pub fn is_eof(&self) -> bool {
-            self.curr_pos == self.data.len()
+        let mut source = String::new();
+        let mut trivia = Vec::new();
+        while let Some((leading, token)) = l.tok_with_trivia() {
+            source.push_str(&leading);
+            source.push_str(l.last_match().unwrap());
+            trivia.push((leading, token));
         }
+        source.push_str(l.remaining());
+
+        assert_eq!(source, input);
+        assert_eq!(trivia, vec![
+            (String::from("  "), Token2::Ident(String::from("foo"))),
+            (String::from(" \t "), Token2::Ident(String::from("bar"))),
+            (String::from(" # comment\n\n"), Token2::Ident(String::from("baz"))),
+        ]);
+        assert_eq!(l.remaining(), "\n# end\n");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use core::panic;
+    #[test]
+    fn current_line(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+        l.init(String::from("foo bar\nbaz qux quux\n\nend"));
+        assert_eq!((l.line_start(), l.current_line()), (0, "foo bar"));
+
+        l.tok(true);
+        l.tok(true);
+        // The line break has not been skipped yet
+        assert_eq!((l.line_start(), l.current_line()), (0, "foo bar"));
+
+        l.tok(true);
+        l.tok(true);
+        assert_eq!(l.position(), (2, 8));
+        assert_eq!((l.line_start(), l.current_line()), (8, "baz qux quux"));
 
-    use crate::lexer::{Lexer, LexerBuilder, LexAction};
+        l.tok(true);
+        l.tok(true);
+        assert_eq!((l.line_start(), l.current_line()), (22, "end"));
+    }
 
     #[test]
-    fn it_works() {
-        let result = 2 + 2;
-        assert_eq!(result, 4);
+    fn push_any(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_any(&[r"<=", r">=", r"==", r"!=", r"<", r">"], |x: &str| Token2::Keyword(String::from(x)))
+            .push_any(&[r"[a-z]+", r"\d+|_"], |x: &str| Token2::Ident(String::from(x)))
+            .build();
+        l.init(String::from("a <= b>c == 1 != _ >= <"));
+
+        // Each call is a single rule
+        let tokens: Vec<_> = std::iter::from_fn(|| l.tok_with_rule(true)).collect();
+        let keyword = |x: &str| (Token2::Keyword(String::from(x)), 0);
+        let ident = |x: &str| (Token2::Ident(String::from(x)), 1);
+        assert_eq!(tokens, vec![
+            ident("a"), keyword("<="), ident("b"), keyword(">"), ident("c"), keyword("=="),
+            ident("1"), keyword("!="), ident("_"), keyword(">="), keyword("<"),
+        ]);
+        assert_eq!(l.rule_count(), 2);
+        assert_eq!(l.patterns().len(), 2);
+
+        // The longest match wins, no matter the order of the patterns
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_any(&[r"<", r"<=", r"<<="], |x: &str| Token2::Keyword(String::from(x)))
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+        l.init(String::from("<= < <<= x"));
+        assert_eq!(l.tokenize_all(true), Ok(vec![
+            Token2::Keyword(String::from("<=")), Token2::Keyword(String::from("<")), Token2::Keyword(String::from("<<=")), Token2::Ident(String::from("x")),
+        ]));
+        assert_eq!(l.peek_raw(true), None);
+        l.reset();
+        assert_eq!(l.peek_raw(true), Some((0, 0..2)));
+        assert!(l.matches_rule(0));
+
+        // Disabling the rule disables all of its patterns
+        l.set_rule_enabled(0, false);
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+
+        let mut builder = LexerBuilder::<Token2>::new();
+        builder.push_any(&[r"<", r"<="], |x: &str| Token2::Keyword(String::from(x)))
+            .push_str(r"<=", Token2::Ident);
+        assert_eq!(builder.validate(), vec![Warning{ rule: 1, pattern: String::from("<="), shadowed_by: 0 }]);
     }
 
-    #[derive(Clone)]
-    enum Token1 {
-        TokenInt    (i32),
-        TokenString (String),
+    #[test]
+    fn into_parts(){
+        use regex_automata::{Anchored, Input, PatternSet};
+
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .push(r"[a-z]",        |_: &str| unreachable!())
+            .build();
+        l.init(String::from("skipped 12 ab3 c 4"));
+        l.tok(true);
+
+        let parts: LexerParts<Token1> = l.into_parts();
+        assert_eq!(parts.rules.len(), 3);
+        let anchored = |pos: usize| Input::new(parts.data.as_ref()).range(pos..parts.end).anchored(Anchored::Yes);
+        let mut pos = parts.position;
+        let mut tokens = Vec::new();
+        loop {
+            if let Some(m) = parts.whitespace.search(&anchored(pos)) {
+                pos = m.end();
+            }
+            if pos == parts.end {
+                break;
+            }
+            // The longest match wins, ties are won by the first rule
+            let mut matching = PatternSet::new(parts.rules.len());
+            parts.rule_set.which_overlapping_matches(&anchored(pos), &mut matching);
+            let (end, rule) = matching.iter()
+                .map(|rule| (parts.rules[rule.as_usize()].search(&anchored(pos)).unwrap().end(), rule.as_usize()))
+                .max_by_key(|&(end, rule)| (end, std::cmp::Reverse(rule)))
+                .unwrap();
+            let Action::Token(action) = &parts.actions[rule] else { unreachable!() };
+            tokens.push(action(&parts.data[pos..end]));
+            pos = end;
+        }
+
+        assert_eq!(tokens, vec![
+            Token1::TokenInt(12),
+            Token1::TokenString(String::from("ab3")),
+            Token1::TokenString(String::from("c")),
+            Token1::TokenInt(4),
+        ]);
+        assert_eq!(parts.priorities, vec![0, 0, 0]);
     }
 
     #[test]
-    fn doesnt_panic_array(){
-        let _l: Lexer<Token1> = LexerBuilder{
-            actions: [LexAction{ token: r"\d+", action: |x: &str| Token1::TokenInt( x.parse::<i32>().unwrap() )}].to_vec(),
-        }.build();
+    fn crlf(){
+        let builder = || {
+            let mut builder = LexerBuilder::<Token2>::new();
+            builder.push_str(r"[a-z]+", Token2::Ident);
+            builder
+        };
+        let ident = |x: &str| Token2::Ident(String::from(x));
+
+        let mut l = builder().build();
+        l.init(String::from("ab\r\ncd\r\n\r\n  ef\r\n"));
+        assert_eq!(l.tok(true), Some(ident("ab")));
+        assert_eq!(l.position(), (1, 3));
+        assert_eq!(l.current_line(), "ab");
+        assert_eq!(l.tok(true), Some(ident("cd")));
+        assert_eq!(l.position(), (2, 3));
+        assert_eq!(l.tok(true), Some(ident("ef")));
+        assert_eq!(l.position(), (4, 5));
+        assert_eq!(l.current_line(), "  ef");
+        assert_eq!(l.try_tok(true).unwrap(), None);
+        assert_eq!(l.position(), (5, 1));
+        assert_eq!(l.line_col(8), (3, 1));
+
+        let mut l = builder()
+            .push(r"\n", |_: &str| Token2::Select)
+            .significant_newlines(true)
+            .build();
+        l.init(String::from("ab\r\ncd \r\n"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![ident("ab"), Token2::Select, ident("cd"), Token2::Select]);
+        assert_eq!(l.position(), (3, 1));
     }
 
     #[test]
-    fn doesnt_panic_append(){
-        let _l: Lexer<Token1> = LexerBuilder::new()
-            .push( r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
-            .push( r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+    fn ignore(){
+        let mut l = LexerBuilder::<Token1>::new()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .whitespace(r" +")
+            .ignore(r"\n+")
+            .ignore(r"/\*([^*]|\*+[^*/])*\*+/")
             .build();
+        l.init(String::from("1 /* a */2/**/ /* b * c **/\n  /* d */ x /*3*/"));
+
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token1::TokenInt(1),
+            Token1::TokenInt(2),
+            Token1::TokenString(String::from("x")),
+        ]);
+
+        // Comments are only skipped together with the whitespace
+        l.init(String::from("/* a */1"));
+        assert!(l.try_tok(false).is_err());
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+
+        let mut builder = LexerBuilder::<Token1>::new();
+        builder.push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap())).ignore(r"(");
+        assert_eq!(builder.try_build().unwrap_err().pattern, "(");
     }
 
     #[test]
-    fn simple_number_test(){
+    fn rule_names(){
         let mut l = LexerBuilder::<Token1>::new()
-            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push_named("number",     r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push_named("identifier", r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .push(r"\+", |x: &str| Token1::TokenString(String::from(x)))
+            .eof_token(|| Token1::TokenInt(-1))
             .build();
+        l.init(String::from("x + 1"));
 
-        l.init(String::from("42"));
+        let rules: Vec<_> = std::iter::from_fn(|| l.tok_with_rule(true)).map(|(_, rule)| rule).collect();
+        let names: Vec<_> = rules.into_iter().map(|rule| l.rule_name(rule)).collect();
+        assert_eq!(names, vec![Some("identifier"), None, Some("number"), None]);
 
-        match l.tok(true).unwrap() {
-            Token1::TokenInt(v) => { assert!(v == 42);},
-            _ => { panic!("Token is not of type int"); },
+        l.init(String::from("1"));
+        assert_eq!(l.peek_raw(true).and_then(|(rule, _)| l.rule_name(rule)), Some("number"));
+        assert_eq!(l.rule_name(7), None);
+    }
+
+    #[test]
+    fn rule_checked_on_its_own(){
+        // A leading `|` only applies to the rule itself. As in the regex
+        // crate the empty alternative is preferred, so the rule could never
+        // produce a token.
+        let error = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]", Token2::Keyword)
+            .push_str(r"|abc", Token2::Ident)
+            .push_str(r"x|yz", Token2::Ident)
+            .try_build()
+            .unwrap_err();
+        assert_eq!(error, BuildError{ pattern: String::from("|abc"), rule: Some(1), kind: BuildErrorKind::EmptyMatch });
+        assert_eq!(error.to_string(), "invalid pattern `|abc` of rule 1: the pattern only matches the empty string");
+        for pattern in [r"a*?", r"^", r"(|a)"] {
+            let error = LexerBuilder::<Token2>::new().push_str(pattern, Token2::Ident).try_build().unwrap_err();
+            assert_eq!((error.rule, &error.kind), (Some(0), &BuildErrorKind::EmptyMatch));
         }
+        let error = LexerBytesBuilder::<Token1>::new()
+            .push(r"|abc", |_: &[u8]| Token1::TokenInt(0))
+            .try_build()
+            .err()
+            .unwrap();
+        assert_eq!((error.rule, &error.kind), (Some(0), &BuildErrorKind::EmptyMatch));
+
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]", Token2::Keyword)
+            .push_str(r"x|yz", Token2::Ident)
+            .build();
+        l.init(String::from("abyz"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Token2::Keyword(String::from("a")),
+            Token2::Keyword(String::from("b")),
+            Token2::Ident(String::from("yz")),
+        ]);
+
+        // Invalid on its own, but valid once wrapped in the flags as `(?i:a)|(b)`
+        let error = LexerBuilder::<Token2>::new()
+            .push(r"if", |_: &str| Token2::If)
+            .push_with_flags(r"a)|(b", "i", |_: &str| Token2::Select)
+            .try_build()
+            .unwrap_err();
+        assert_eq!((error.pattern.as_str(), error.rule), ("a)|(b", Some(1)));
+        assert!(error.to_string().starts_with("invalid pattern `a)|(b` of rule 1: "));
+
+        let error = LexerBuilder::<Token2>::new()
+            .push(r"if", |_: &str| Token2::If)
+            .push(r"(", |_: &str| Token2::Select)
+            .try_build()
+            .unwrap_err();
+        assert_eq!(error.rule, Some(1));
+        let error = LexerBuilder::<Token2>::new()
+            .push(r"if", |_: &str| Token2::If)
+            .whitespace(r"(")
+            .try_build()
+            .unwrap_err();
+        assert_eq!(error.rule, None);
     }
 
     #[test]
-    fn simple_number_leading_ws(){
-        let mut l = LexerBuilder::<Token1>::new()
-            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+    fn indentation(){
+        #[derive(Debug, PartialEq)]
+        enum Tok {
+            Word(char),
+            Colon,
+            Indent,
+            Dedent,
+        }
+
+        fn indentation(indent: usize, levels: &mut Vec<usize>) -> Vec<Tok> {
+            let mut tokens = Vec::new();
+            if indent > levels.last().copied().unwrap_or(0) {
+                levels.push(indent);
+                tokens.push(Tok::Indent);
+            }
+            while levels.last().is_some_and(|&level| level > indent) {
+                levels.pop();
+                tokens.push(Tok::Dedent);
+            }
+            tokens
+        }
+
+        let mut l = LexerBuilder::<Tok>::new()
+            .push(r"[a-z]", |x: &str| Tok::Word(x.chars().next().unwrap()))
+            .push(r":",     |_: &str| Tok::Colon)
+            .on_line_start(indentation)
             .build();
+        use Tok::*;
 
-        l.init(String::from(" 42"));
+        l.init(String::from("a:\n  b:\n    c\n\n  d c\nd"));
+        assert_eq!(std::iter::from_fn(|| l.tok(true)).collect::<Vec<_>>(), vec![
+            Word('a'), Colon, Indent, Word('b'), Colon, Indent, Word('c'), Dedent, Word('d'), Word('c'), Dedent, Word('d'),
+        ]);
 
-        match l.tok(true).unwrap() {
-            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
-            _ => { panic!("Token is not of type int"); },
+        // The remaining levels are closed at the end of input
+        l.init(String::from("a:\n  b:\n    c"));
+        assert_eq!(l.tok_spanned(true), Some((Word('a'), 0..1)));
+        assert_eq!(l.tok_spanned(true), Some((Colon, 1..2)));
+        assert_eq!(l.tok_spanned(true), Some((Indent, 5..5)));
+        let checkpoint = l.checkpoint();
+        let rest: Vec<_> = std::iter::from_fn(|| l.tok_spanned(true)).collect();
+        assert_eq!(rest, vec![
+            (Word('b'), 5..6), (Colon, 6..7), (Indent, 12..12), (Word('c'), 12..13), (Dedent, 13..13), (Dedent, 13..13),
+        ]);
+        l.restore(checkpoint);
+        assert_eq!(std::iter::from_fn(|| l.tok_spanned(true)).collect::<Vec<_>>(), rest);
+
+        // Returning a token keeps the indentation levels
+        l.init(String::from("a\n  b c"));
+        assert_eq!(std::iter::from_fn(|| l.tok(true)).take(4).collect::<Vec<_>>(), vec![Word('a'), Indent, Word('b'), Word('c')]);
+        assert!(l.unput());
+        assert_eq!(std::iter::from_fn(|| l.tok(true)).collect::<Vec<_>>(), vec![Word('c'), Dedent]);
+
+        // The tokens of the final call are not past the end of input
+        l.init(String::from("a:\n  b:\n    c\n"));
+        let mut tokens = Vec::new();
+        while !l.is_eof() {
+            tokens.extend(l.tok(true));
+        }
+        assert_eq!(tokens, vec![Word('a'), Colon, Indent, Word('b'), Colon, Indent, Word('c'), Dedent, Dedent]);
+        assert_eq!(l.tok(true), None);
+
+        // Lines with only skipped input are not indented
+        let mut l = LexerBuilder::<Tok>::new()
+            .push(r"[a-z]", |x: &str| Tok::Word(x.chars().next().unwrap()))
+            .push(r":",     |_: &str| Tok::Colon)
+            .push_skip(r"#[^\n]*")
+            .on_line_start(indentation)
+            .build();
+        l.init(String::from("a:\n    # c\n  b\n# d\n  c"));
+        assert_eq!(std::iter::from_fn(|| l.tok_spanned(true)).collect::<Vec<_>>(), vec![
+            (Word('a'), 0..1), (Colon, 1..2), (Indent, 13..13), (Word('b'), 13..14), (Word('c'), 21..22), (Dedent, 22..22),
+        ]);
+
+        // The hook runs once per line and at the end of input
+        thread_local! {
+            static CALLS: Cell<usize> = const { Cell::new(0) };
+        }
+        fn counted(indent: usize, levels: &mut Vec<usize>) -> Vec<Tok> {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            indentation(indent, levels)
         }
+        let mut l = LexerBuilder::<Tok>::new()
+            .push(r"[a-z]", |x: &str| Tok::Word(x.chars().next().unwrap()))
+            .push(r":",     |_: &str| Tok::Colon)
+            .on_line_start(counted)
+            .build();
+        l.init(String::from("a:\n  b:\n    c"));
+        assert_eq!(std::iter::from_fn(|| l.tok(true)).count(), 9);
+        assert_eq!(CALLS.with(Cell::get), 4);
     }
 
     #[test]
-    fn two_numbers(){
-        let mut l = LexerBuilder::<Token1>::new()
-            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+    fn size_limit(){
+        let build = |limit: Option<usize>| {
+            let mut builder = LexerBuilder::<Token2>::new();
+            builder.push_str(r"[a-z]+", Token2::Ident)
+                .push_str(r"[a-z]{1000}", Token2::Keyword)
+                .dfa_size_limit(1 << 16);
+            if let Some(limit) = limit {
+                builder.size_limit(limit);
+            }
+            builder.try_build()
+        };
+
+        let error = build(Some(10_000)).unwrap_err();
+        assert!(matches!(error.kind, BuildErrorKind::Regex(regex::Error::CompiledTooBig(10_000))));
+        assert!(build(None).is_ok());
+
+        // Rules compiled on their first match and added later keep the limit
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .size_limit(10_000)
             .build();
+        l.init(String::from("ab"));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("ab"))));
+        assert_eq!(l.add_rule(r"[a-z]{1000}", |_: &str| Token2::If).unwrap_err().rule, Some(1));
 
-        l.init(String::from("42 52"));
+        // The rule fits the limit on its own, but not together with the others
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]{80}", Token2::Ident)
+            .size_limit(10_000)
+            .build();
+        let error = l.add_rule(r"[a-z]{80}x", |_: &str| Token2::If).unwrap_err();
+        assert_eq!((error.rule, &error.kind), (Some(1), &BuildErrorKind::Regex(regex::Error::CompiledTooBig(10_000))));
+    }
 
-        match l.tok(true).unwrap() {
-            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual {}", v);},
-            _ => { panic!("Token is not of type int"); },
+    #[test]
+    fn lex_single_token(){
+        let rules = [
+            LexAction::new(r"\d+",          Action::Token(Rc::new(|x: &str| Token1::TokenInt(x.parse::<i32>().unwrap())))),
+            LexAction::new(r"[a-zA-Z_]\w*", Action::Token(Rc::new(|x: &str| Token1::TokenString(String::from(x))))),
+        ];
+
+        assert_eq!(lex_one(&rules, "123"), Some((Token1::TokenInt(123), 3)));
+        assert_eq!(lex_one(&rules, "ab c"), Some((Token1::TokenString(String::from("ab")), 2)));
+        assert_eq!(lex_one(&rules, " 1"), None);
+        assert_eq!(lex_one(&rules, ""), None);
+        assert_eq!(lex_one::<Token1>(&[], "1"), None);
+    }
+
+    #[test]
+    fn rest_of_line(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_rest_of_line(r"echo", |x: &str| Token2::Keyword(String::from(x)))
+            .push_str(r"[a-z]+", Token2::Ident)
+            .build();
+        l.init(String::from("echo hello world\nnext\necho\r\necho  a \\n"));
+
+        assert_eq!(l.tok_spanned(true), Some((Token2::Keyword(String::from("hello world")), 0..16)));
+        assert_eq!(l.tok(true), Some(Token2::Ident(String::from("next"))));
+        assert_eq!(l.tok_spanned(true), Some((Token2::Keyword(String::new()), 22..26)));
+        assert_eq!(l.tok(true), Some(Token2::Keyword(String::from("a \\n"))));
+        assert_eq!(l.tok(true), None);
+    }
+
+    #[test]
+    fn lex_on_threads(){
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Lexer<Token1, Shared>>();
+
+        let lexer = LexerBuilder::<Token1, Shared>::default()
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .build();
+        let inputs: Vec<String> = (0..4).map(|i| format!("worker {} {}", i, i * 10)).collect();
+
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let workers: Vec<_> = inputs.iter().map(|input| {
+                let mut lexer = lexer.clone_fresh();
+                scope.spawn(move || {
+                    lexer.init_str(input);
+                    lexer.tokenize_all(true).unwrap()
+                })
+            }).collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+
+        for (i, tokens) in results.into_iter().enumerate() {
+            let i = i as i32;
+            assert_eq!(tokens, vec![Token1::TokenString(String::from("worker")), Token1::TokenInt(i), Token1::TokenInt(i * 10)]);
         }
- 
-        match l.tok(true).unwrap() {
-            Token1::TokenInt(v) => { assert!(v == 52);},
-            _ => { panic!("Token is not of type int"); },
-        }       
+
+        // The Lexer can also be shared by reference
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert_eq!(lexer.patterns().len(), 2));
+        });
     }
 
     #[test]
-    fn many_numbers(){
+    fn skipped_whitespace_never_in_token(){
+        // A rule that could match with leading whitespace only sees it if
+        // whitespace is not skipped, as all rules are anchored after the skip
+        let build = || LexerBuilder::<Token2>::new()
+            .push_str(r"\s*=",  Token2::Keyword)
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push_str(r"\s+",   Token2::Keyword)
+            .build();
+        let keyword = |x: &str| Token2::Keyword(String::from(x));
+        let ident = |x: &str| Token2::Ident(String::from(x));
+
+        type Case = (&'static str, bool, Vec<(Token2, Range<usize>)>);
+        let cases: [Case; 6] = [
+            ("a =b",      true,  vec![(ident("a"), 0..1), (keyword("="), 2..3), (ident("b"), 3..4)]),
+            ("a =b",      false, vec![(ident("a"), 0..1), (keyword(" ="), 1..3), (ident("b"), 3..4)]),
+            ("  a",       true,  vec![(ident("a"), 2..3)]),
+            ("  a",       false, vec![(keyword("  "), 0..2), (ident("a"), 2..3)]),
+            (" \t\n= ",  true,  vec![(keyword("="), 3..4)]),
+            (" \t\n= ",  false, vec![(keyword(" \t\n="), 0..4), (keyword(" "), 4..5)]),
+        ];
+        for (input, skip_ws, expected) in cases {
+            let mut l = build();
+            l.init(String::from(input));
+            let tokens: Vec<_> = std::iter::from_fn(|| l.tok_spanned(skip_ws)).collect();
+            assert_eq!(tokens, expected, "{:?} with skip_ws {}", input, skip_ws);
+            if skip_ws {
+                assert!(tokens.iter().all(|(_, span)| !input[span.clone()].starts_with(char::is_whitespace)));
+            }
+        }
+
+        // The same holds for the error token, tokens of Skip rules and when
+        // newlines are left to the rules
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"[a-z]+", Token2::Ident)
+            .push_str(r"\s*\n", Token2::Keyword)
+            .push_skip(r"[ \t]*#")
+            .error_token(|x: &str| Token2::Keyword(String::from(x)))
+            .significant_newlines(true)
+            .build();
+        l.init(String::from("a  \n  # ? b"));
+        assert_eq!(std::iter::from_fn(|| l.tok_spanned(true)).collect::<Vec<_>>(), vec![
+            (ident("a"), 0..1), (keyword("\n"), 3..4), (keyword("?"), 8..9), (ident("b"), 10..11),
+        ]);
+    }
+
+    #[test]
+    fn last_error(){
         let mut l = LexerBuilder::<Token1>::new()
             .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
             .build();
-        
-        l.init((0..100).map(|x: i8| x.to_string()).collect::<Vec<String>>().join(" "));
+        l.init(String::from("1\n 2 ? 3"));
+        assert!(l.last_error().is_none());
 
-        for i in 0..100 {
-            match l.tok(true).unwrap() {
-                Token1::TokenInt(v) => { assert!(v == i, "Expected {}: Actual {}", i, v);},
-                _ => { panic!("Token is not of type int"); },
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(1)));
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(2)));
+        assert!(l.last_error().is_none());
+        assert_eq!(l.tok(true), None);
+        assert_eq!(l.last_error(), Some(&LexError{
+            kind: LexErrorKind::Unmatched,
+            position: 5,
+            line: 2,
+            column: 4,
+            snippet: String::from("? 3"),
+            message: None,
+        }));
+
+        l.skip_until(r"\?");
+        assert_eq!(l.tok(true), Some(Token1::TokenInt(3)));
+        assert!(l.last_error().is_none());
+        assert_eq!(l.tok(true), None);
+        assert!(l.last_error().is_none());
+
+        l.init(String::from("?"));
+        l.tok(true);
+        assert!(l.last_error().is_some());
+        l.reset();
+        assert!(l.last_error().is_none());
+    }
+
+    #[test]
+    fn push_pattern(){
+        #[derive(Debug, PartialEq)]
+        enum Tok {
+            Int(i32),
+            Other(String),
+        }
+
+        impl From<&str> for Tok {
+            fn from(x: &str) -> Self {
+                Tok::Other(String::from(x))
             }
         }
+
+        let mut l = LexerBuilder::<Tok>::new()
+            .push(r"\d+", |x: &str| Tok::Int(x.parse::<i32>().unwrap()))
+            .push_pattern(r"[a-z]+")
+            .push_pattern(r"[+*]")
+            .build();
+        l.init(String::from("a + 12 * bc"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec![
+            Tok::Other(String::from("a")),
+            Tok::Other(String::from("+")),
+            Tok::Int(12),
+            Tok::Other(String::from("*")),
+            Tok::Other(String::from("bc")),
+        ]);
+
+        let mut l = LexerBuilder::<String>::new().push_pattern(r"\S+").build();
+        l.init(String::from("x = 1"));
+        assert_eq!(l.tokenize_all(true).unwrap(), vec!["x", "=", "1"]);
     }
 
     #[test]
-    fn test_eof(){
+    fn range_spans_absolute(){
         let mut l = LexerBuilder::<Token1>::new()
-            .push(r"\d+", |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
             .build();
+        let doc = "header:\n  x 42 ? tail";
+        l.init_str_range(doc, 10..17);
+        assert_eq!(l.range(), 10..17);
 
-        l.init(String::from("42"));
+        assert_eq!(l.peek_raw(true), Some((1, 10..11)));
+        assert_eq!(l.tok_spanned(true), Some((Token1::TokenString(String::from("x")), 10..11)));
+        assert_eq!(&doc[10..11], "x");
+        assert_eq!(l.offset(), 11);
+        let tokens: Vec<_> = l.spanned_tokens().collect();
+        assert_eq!(tokens[0], Ok((Token1::TokenInt(42), 12..14)));
+        assert_eq!(tokens[1].as_ref().unwrap_err().position, 15);
 
-        assert!(!l.is_eof());
+        // Relative to the range
+        let start = l.range().start;
+        l.reset();
+        assert_eq!(l.tok_spanned(true).map(|(_, span)| span.start - start..span.end - start), Some(0..1));
+    }
 
-        match l.tok(true).unwrap() {
-            Token1::TokenInt(v) => { assert!(v == 42, "Expected 42: Actual: {}", v);},
-            _ => { panic!("Token is not of type int"); },
+    #[test]
+    fn rule_count(){
+        let mut builder = LexerBuilder::<Token1>::new();
+        assert_eq!(builder.rule_count(), 0);
+        builder
+            .push(r"\d+",          |x: &str| Token1::TokenInt(x.parse::<i32>().unwrap()))
+            .push(r"[a-zA-Z_]\w*", |x: &str| Token1::TokenString(String::from(x)))
+            .push_skip(r"\+")
+            .whitespace(r"[ \t]+")
+            .ignore(r"#[^\n]*");
+        assert_eq!(builder.rule_count(), 3);
+
+        let l = builder.build();
+        assert_eq!(l.rule_count(), 3);
+        assert_eq!(l.rule_count(), l.patterns().len());
+    }
+
+    #[test]
+    fn set_rule_enabled(){
+        let mut l = LexerBuilder::<Token2>::new()
+            .push_str(r"if|else",      Token2::Keyword)
+            .push_str(r"[a-zA-Z_]\w*", Token2::Ident)
+            .build();
+        let keyword = |x: &str| Token2::Keyword(String::from(x));
+        let ident = |x: &str| Token2::Ident(String::from(x));
+
+        l.init(String::from("if x else y if"));
+        assert_eq!(l.tok(true), Some(keyword("if")));
+        assert_eq!(l.tok(true), Some(ident("x")));
+
+        l.set_rule_enabled(0, false);
+        assert_eq!(l.tok(true), Some(ident("else")));
+        assert_eq!(l.tok(true), Some(ident("y")));
+
+        l.set_rule_enabled(0, true);
+        assert_eq!(l.tok(true), Some(keyword("if")));
+        assert_eq!(l.tok(true), None);
+
+        // Tokens peeked before toggling a rule are lexed again
+        l.init(String::from("else"));
+        assert_eq!(l.peek(true), Some(&keyword("else")));
+        l.set_rule_enabled(0, false);
+        assert_eq!(l.tok(true), Some(ident("else")));
+
+        // Without any enabled rule nothing matches
+        l.set_rule_enabled(1, false);
+        l.init(String::from("x"));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+    }
+
+    #[test]
+    fn lexical_modes(){
+        #[derive(Debug, PartialEq)]
+        enum Tok {
+            Quote,
+            Text(String),
+            InterpStart,
+            InterpEnd,
+            Ident(String),
         }
 
-        assert!(l.is_eof());
+        let mut l = LexerBuilder::<Tok>::new()
+            .push_transition(None,            "\"",         ModeChange::Push("string"), |_: &str| Tok::Quote)
+            .push(r"[a-z]+",                                                              |x: &str| Tok::Ident(String::from(x)))
+            .push_transition(Some("string"),  "\"",         ModeChange::Pop,            |_: &str| Tok::Quote)
+            .push_transition(Some("string"),  r"\$\{",      ModeChange::Push("interp"), |_: &str| Tok::InterpStart)
+            .push_in_mode("string",           r#"[^"$]+"#,                                |x: &str| Tok::Text(String::from(x)))
+            .push_transition(Some("interp"),  r"\}",        ModeChange::Pop,            |_: &str| Tok::InterpEnd)
+            .push_in_mode("interp",           r"[a-z]+",                                  |x: &str| Tok::Ident(String::from(x)))
+            .build();
+        assert!(l.mode().is_none());
+
+        // Whitespace is part of the text of a string
+        l.init(String::from("a \"abc ${x} def\" b"));
+        let mut tokens = Vec::new();
+        while let Some(token) = l.tok(l.mode() != Some("string")) {
+            tokens.push((token, l.mode().map(String::from)));
+        }
+        let string = || Some(String::from("string"));
+        assert_eq!(tokens, vec![
+            (Tok::Ident(String::from("a")), None),
+            (Tok::Quote, string()),
+            (Tok::Text(String::from("abc ")), string()),
+            (Tok::InterpStart, Some(String::from("interp"))),
+            (Tok::Ident(String::from("x")), Some(String::from("interp"))),
+            (Tok::InterpEnd, string()),
+            (Tok::Text(String::from(" def")), string()),
+            (Tok::Quote, None),
+            (Tok::Ident(String::from("b")), None),
+        ]);
+
+        // Rules of other modes do not match
+        l.init(String::from("}"));
+        assert_eq!(l.try_tok(true).unwrap_err().kind, LexErrorKind::Unmatched);
+
+        // Peeking and restoring a checkpoint keep the mode
+        l.init(String::from("\"x\""));
+        assert_eq!(l.peek(true), Some(&Tok::Quote));
+        assert!(l.mode().is_none());
+        let checkpoint = l.checkpoint();
+        assert_eq!(l.tok(true), Some(Tok::Quote));
+        assert_eq!(l.mode(), Some("string"));
+        l.restore(checkpoint);
+        assert!(l.mode().is_none());
+
+        // Returning a token restores the mode in front of it
+        l.init(String::from("\"abc\""));
+        assert_eq!(l.tok(true), Some(Tok::Quote));
+        assert_eq!(l.tok(false), Some(Tok::Text(String::from("abc"))));
+        assert!(l.unput());
+        assert_eq!(l.mode(), Some("string"));
+        assert_eq!(l.tok(false), Some(Tok::Text(String::from("abc"))));
+        let (token, span) = l.tok_spanned(false).unwrap();
+        assert_eq!(token, Tok::Quote);
+        assert!(l.mode().is_none());
+        l.backtrack(span.start);
+        assert_eq!(l.mode(), Some("string"));
+        assert_eq!(l.tok(false), Some(Tok::Quote));
+
+        // Modes can be changed by hand
+        l.push_mode("interp");
+        assert_eq!(l.tok(true), None);
+        assert_eq!(l.pop_mode(), Some(String::from("interp")));
+        assert_eq!(l.pop_mode(), None);
+        l.reset();
+        l.push_mode("string");
+        l.reset();
+        assert!(l.mode().is_none());
     }
 }